@@ -1,3 +1,7 @@
 fn main() {
-    embuild::espidf::sysenv::output();
+    // The ESP-IDF build environment only exists when building the firmware, not when running the
+    // library's tests on the host.
+    if std::env::var("CARGO_CFG_TARGET_OS").as_deref() == Ok("espidf") {
+        embuild::espidf::sysenv::output();
+    }
 }
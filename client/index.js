@@ -73,3 +73,129 @@ const watchFreqSliders = () => {
 
   freqSliderEventsRegistered = true;
 };
+
+// Keep the number displayed next to a slider in sync with the slider.
+const updateSliderValue = (slider, valueId) => {
+  document.getElementById(valueId).textContent = slider.value;
+};
+
+// Format a number of seconds as `m:ss`.
+const formatCountdown = (seconds) =>
+  `${Math.floor(seconds / 60)}:${String(seconds % 60).padStart(2, "0")}`;
+
+// The server only tells us how long until the next squirt every few seconds, so
+// we count down in between.
+setInterval(() => {
+  const countdown = document.getElementById("auto-countdown-value");
+
+  if (!countdown) {
+    return;
+  }
+
+  const seconds = Math.max(0, parseInt(countdown.dataset.seconds) - 1);
+
+  countdown.dataset.seconds = seconds;
+  countdown.textContent = formatCountdown(seconds);
+}, 1000);
+
+// Only show the inputs for the parameters of the selected interval
+// distribution.
+const showDistributionParams = (select) => {
+  for (const param of document.querySelectorAll("[data-distributions]")) {
+    param.hidden = !param.dataset.distributions.split(" ").includes(select.value);
+  }
+};
+
+// How often to tell the server the HOLD button is still held down. This needs
+// to be comfortably shorter than `io.hold_timeout`, or the pump will stop.
+const HOLD_KEEPALIVE_INTERVAL = 250;
+
+let currentHold = null;
+
+const sendHoldUpdate = (action, id) =>
+  fetch(`/api/hold/${action}`, {
+    method: "POST",
+    body: new URLSearchParams({ id }),
+  });
+
+// Start running the pump, and keep it running until the button is released.
+// If we stop sending keep-alives (e.g. because the page was closed), the
+// server stops the pump on its own.
+const startHold = async (button) => {
+  if (currentHold) {
+    return;
+  }
+
+  const hold = { id: null, interval: null, released: false };
+  currentHold = hold;
+  button.setAttribute("aria-pressed", "true");
+
+  const body = new URLSearchParams();
+  const intensity = document.getElementById("intensity-input");
+
+  if (intensity && !intensity.disabled) {
+    body.set("intensity", intensity.value);
+  }
+
+  try {
+    const resp = await fetch("/api/hold", {
+      method: "POST",
+      headers: { Accept: "application/json" },
+      body,
+    });
+
+    if (!resp.ok) {
+      releaseHold(button);
+      return;
+    }
+
+    hold.id = (await resp.json()).id;
+  } catch {
+    releaseHold(button);
+    return;
+  }
+
+  // The button may have been released while we were waiting for the server.
+  if (hold.released) {
+    sendHoldUpdate("release", hold.id);
+    return;
+  }
+
+  hold.interval = setInterval(
+    () => sendHoldUpdate("keepalive", hold.id),
+    HOLD_KEEPALIVE_INTERVAL
+  );
+};
+
+const releaseHold = (button) => {
+  const hold = currentHold;
+
+  if (!hold) {
+    return;
+  }
+
+  currentHold = null;
+  hold.released = true;
+  button.setAttribute("aria-pressed", "false");
+  clearInterval(hold.interval);
+
+  if (hold.id !== null) {
+    sendHoldUpdate("release", hold.id);
+  }
+};
+
+// HTMX doesn't swap in error responses by default, but for some requests (e.g.
+// when the toy is busy, rate-limited, or disarmed), the server sends back a
+// message saying what went wrong.
+document.addEventListener("htmx:beforeSwap", (event) => {
+  const status = event.detail.xhr.status;
+
+  if (
+    event.detail.target.hasAttribute("data-show-errors") &&
+    status >= 400 &&
+    status < 500
+  ) {
+    event.detail.shouldSwap = true;
+    event.detail.isError = false;
+  }
+});
@@ -11,10 +11,18 @@ use esp_idf_svc::sys::ESP_ERR_NVS_INVALID_LENGTH;
 use esp_idf_svc::wifi;
 use serde::Deserialize;
 
+use crate::io::{
+    self, DistributionKind, GovernorLimits, IntervalDistribution, MessageTemplate, Pattern, Program,
+};
+
 const TOML_CONFIG: &str = include_str!("../config.toml");
 
 const NVS_USER_NAMESPACE: &str = "user";
 
+// User patterns and programs are each stored as a single JSON blob, which has to be read into
+// memory all at once.
+const MAX_USER_BLOB_SIZE: usize = 8192;
+
 // We store persistent user preferences in their own NVS namespace.
 //
 // If you check the Justfile, you'll see that we erase the NVS partition before flashing the
@@ -52,6 +60,7 @@ struct WifiConfig {
     ssid: Option<String>,
     password: Option<String>,
     hostname: String,
+    max_failures: u32,
     #[serde(rename = "static")]
     static_ip: Option<StaticWifiConfig>,
 }
@@ -63,6 +72,7 @@ struct AccessPointConfig {
     hidden: bool,
     channel: Option<u8>,
     gateway: String,
+    stop_when_empty: bool,
 }
 
 #[derive(Debug, Deserialize)]
@@ -70,16 +80,30 @@ struct HttpConfig {
     port: u16,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IoDriver {
+    I2c,
+    Null,
+}
+
 #[derive(Debug, Deserialize)]
 struct IoConfig {
+    driver: IoDriver,
     sda_pin: u8,
     scl_pin: u8,
     address: u8,
-    message: Vec<u8>,
+    message: MessageTemplate,
+    stop_message: Vec<u8>,
+    default_intensity: u8,
+    hold_message: MessageTemplate,
+    hold_stop_message: Vec<u8>,
+    hold_timeout: u32,
+    hold_max_time: u32,
     baudrate: u32,
     timeout: u32,
     block_time: u32,
-    test_mode: bool,
+    pattern: String,
 }
 
 #[derive(Debug, Deserialize)]
@@ -88,6 +112,47 @@ struct FreqConfig {
     upper_bound: u32,
     default_min: u32,
     default_max: u32,
+    distribution: DistributionKind,
+    skew: u32,
+}
+
+#[derive(Debug, Deserialize)]
+struct LimitsConfig {
+    max_fires: u32,
+    window: u32,
+    max_on_time: u32,
+    cooldown: u32,
+}
+
+#[derive(Debug, Deserialize)]
+struct ArmingConfig {
+    idle_timeout: u32,
+    #[serde(default)]
+    switch_pin: Option<u8>,
+}
+
+#[derive(Debug, Deserialize)]
+struct SafewordConfig {
+    lockout: u32,
+    #[serde(default)]
+    pin: Option<String>,
+    #[serde(default)]
+    button_pin: Option<u8>,
+}
+
+#[derive(Debug, Deserialize)]
+struct HeartbeatConfig {
+    timeout: u32,
+}
+
+#[derive(Debug, Deserialize)]
+struct CheckInConfig {
+    grace: u32,
+}
+
+#[derive(Debug, Deserialize)]
+struct UiConfig {
+    show_countdown: bool,
 }
 
 #[derive(Debug, Deserialize)]
@@ -97,12 +162,32 @@ struct Config {
     http: HttpConfig,
     io: IoConfig,
     frequency: FreqConfig,
+    limits: LimitsConfig,
+    arming: ArmingConfig,
+    safeword: SafewordConfig,
+    heartbeat: HeartbeatConfig,
+    check_in: CheckInConfig,
+    ui: UiConfig,
+    #[serde(default)]
+    patterns: Vec<Pattern>,
+    #[serde(default)]
+    programs: Vec<Program>,
 }
 
 impl Config {
     fn from_file() -> anyhow::Result<Self> {
         log::info!("Reading TOML config file.");
-        Ok(toml::from_str(TOML_CONFIG)?)
+        let config: Self = toml::from_str(TOML_CONFIG)?;
+
+        for pattern in &config.patterns {
+            pattern.validate()?;
+        }
+
+        for program in &config.programs {
+            program.validate()?;
+        }
+
+        Ok(config)
     }
 }
 
@@ -144,6 +229,22 @@ where
     }
 }
 
+impl<P> ValueSource<Vec<u8>> for EspNvs<P>
+where
+    P: NvsPartitionId,
+{
+    fn get_value(&mut self, key: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        // Unlike strings, we can ask NVS how large a blob is up front.
+        let Some(len) = self.blob_len(key)? else {
+            return Ok(None);
+        };
+
+        let mut buf = vec![0; len];
+
+        Ok(self.get_blob(key, &mut buf)?.map(ToOwned::to_owned))
+    }
+}
+
 pub fn wifi_is_configured<P: NvsPartitionId>(nvs_part: EspNvsPartition<P>) -> anyhow::Result<bool> {
     let ssid = wifi_ssid(nvs_part)?;
     Ok(ssid.is_some() && !ssid.as_ref().unwrap().is_empty())
@@ -177,6 +278,29 @@ pub fn set_wifi_ip_addr<P: NvsPartitionId>(
     Ok(())
 }
 
+// Why we last failed to connect to the local network, if we haven't connected since.
+pub fn wifi_failure<P: NvsPartitionId>(
+    nvs_part: EspNvsPartition<P>,
+) -> anyhow::Result<Option<String>> {
+    let mut nvs = user_nvs(nvs_part)?;
+    nvs.get_value("wifi.failure")
+}
+
+pub fn set_wifi_failure<P: NvsPartitionId>(
+    nvs_part: EspNvsPartition<P>,
+    failure: Option<&str>,
+) -> anyhow::Result<()> {
+    let mut nvs = user_nvs(nvs_part)?;
+
+    if let Some(failure) = failure {
+        nvs.set_str("wifi.failure", failure)?;
+    } else {
+        nvs.remove("wifi.failure")?;
+    }
+
+    Ok(())
+}
+
 pub fn wifi_ssid<P: NvsPartitionId>(
     nvs_part: EspNvsPartition<P>,
 ) -> anyhow::Result<Option<String>> {
@@ -225,10 +349,28 @@ pub fn set_wifi_password<P: NvsPartitionId>(
     Ok(())
 }
 
+// Switch to a different local network. Whatever went wrong connecting to the old one might not
+// apply to the new one, so we forget about it.
+pub fn set_wifi_credentials<P: NvsPartitionId>(
+    nvs_part: EspNvsPartition<P>,
+    ssid: Option<&str>,
+    password: Option<&str>,
+) -> anyhow::Result<()> {
+    set_wifi_ssid(nvs_part.clone(), ssid)?;
+    set_wifi_password(nvs_part.clone(), password)?;
+    set_wifi_failure(nvs_part, None)?;
+
+    Ok(())
+}
+
 pub fn wifi_hostname() -> anyhow::Result<String> {
     default_config().map(|config| config.wifi.hostname.clone())
 }
 
+pub fn wifi_max_failures() -> anyhow::Result<u32> {
+    default_config().map(|config| config.wifi.max_failures)
+}
+
 pub fn wifi_static_ip_addr() -> anyhow::Result<Option<Ipv4Addr>> {
     match &default_config()?.wifi.static_ip {
         Some(config) => config
@@ -277,6 +419,10 @@ pub fn access_point_channel() -> anyhow::Result<Option<u8>> {
     default_config().map(|config| config.access_point.channel)
 }
 
+pub fn access_point_stop_when_empty() -> anyhow::Result<bool> {
+    default_config().map(|config| config.access_point.stop_when_empty)
+}
+
 pub fn access_point_gateway() -> anyhow::Result<Ipv4Addr> {
     let gateway = &default_config()?.access_point.gateway;
 
@@ -364,6 +510,8 @@ pub struct IoPins {
     pins: GpioPins,
     sda_pin: u8,
     scl_pin: u8,
+    arm_switch_pin: Option<u8>,
+    safeword_button_pin: Option<u8>,
 }
 
 impl fmt::Debug for IoPins {
@@ -371,6 +519,8 @@ impl fmt::Debug for IoPins {
         f.debug_struct("IoPins")
             .field("sda_pin", &self.sda_pin)
             .field("scl_pin", &self.scl_pin)
+            .field("arm_switch_pin", &self.arm_switch_pin)
+            .field("safeword_button_pin", &self.safeword_button_pin)
             .finish_non_exhaustive()
     }
 }
@@ -383,6 +533,18 @@ impl IoPins {
     pub fn scl_pin(&mut self) -> anyhow::Result<gpio::AnyIOPin> {
         self.pins.io_pin(self.scl_pin)
     }
+
+    pub fn arm_switch_pin(&mut self) -> anyhow::Result<Option<gpio::AnyIOPin>> {
+        self.arm_switch_pin
+            .map(|pin| self.pins.io_pin(pin))
+            .transpose()
+    }
+
+    pub fn safeword_button_pin(&mut self) -> anyhow::Result<Option<gpio::AnyIOPin>> {
+        self.safeword_button_pin
+            .map(|pin| self.pins.io_pin(pin))
+            .transpose()
+    }
 }
 
 pub fn io_pins(pins: gpio::Pins) -> anyhow::Result<IoPins> {
@@ -390,6 +552,8 @@ pub fn io_pins(pins: gpio::Pins) -> anyhow::Result<IoPins> {
         pins: pins.into(),
         sda_pin: default_config()?.io.sda_pin,
         scl_pin: default_config()?.io.scl_pin,
+        arm_switch_pin: default_config()?.arming.switch_pin,
+        safeword_button_pin: default_config()?.safeword.button_pin,
     })
 }
 
@@ -397,10 +561,52 @@ pub fn io_address() -> anyhow::Result<u8> {
     default_config().map(|config| config.io.address)
 }
 
-pub fn io_message() -> anyhow::Result<Vec<u8>> {
+pub fn io_message() -> anyhow::Result<MessageTemplate> {
     default_config().map(|config| config.io.message.clone())
 }
 
+pub fn io_stop_message() -> anyhow::Result<Vec<u8>> {
+    default_config().map(|config| config.io.stop_message.clone())
+}
+
+pub fn io_default_intensity() -> anyhow::Result<u8> {
+    default_config().map(|config| config.io.default_intensity)
+}
+
+// The message to start the pump in hold-to-fire mode, or `None` if hold-to-fire is disabled.
+pub fn io_hold_message() -> anyhow::Result<Option<MessageTemplate>> {
+    let config = default_config()?;
+
+    // We don't let the pump start unless we have some way to stop it.
+    if config.io.hold_message.is_empty() || io_hold_stop_message()?.is_empty() {
+        return Ok(None);
+    }
+
+    Ok(Some(config.io.hold_message.clone()))
+}
+
+pub fn io_hold_stop_message() -> anyhow::Result<Vec<u8>> {
+    let config = default_config()?;
+
+    Ok(if config.io.hold_stop_message.is_empty() {
+        config.io.stop_message.clone()
+    } else {
+        config.io.hold_stop_message.clone()
+    })
+}
+
+pub fn io_hold_timeout() -> anyhow::Result<Duration> {
+    Ok(Duration::from_millis(
+        default_config().map(|config| config.io.hold_timeout.into())?,
+    ))
+}
+
+pub fn io_hold_max_time() -> anyhow::Result<Duration> {
+    Ok(Duration::from_millis(
+        default_config().map(|config| config.io.hold_max_time.into())?,
+    ))
+}
+
 pub fn io_baudrate() -> anyhow::Result<u32> {
     default_config().map(|config| config.io.baudrate)
 }
@@ -415,8 +621,46 @@ pub fn io_block_time() -> anyhow::Result<Duration> {
     ))
 }
 
-pub fn io_test_mode() -> anyhow::Result<bool> {
-    default_config().map(|config| config.io.test_mode)
+pub fn io_driver() -> anyhow::Result<IoDriver> {
+    default_config().map(|config| config.io.driver)
+}
+
+pub fn governor_limits() -> anyhow::Result<GovernorLimits> {
+    let limits = &default_config()?.limits;
+
+    Ok(GovernorLimits {
+        max_fires: limits.max_fires,
+        window: Duration::from_secs(limits.window.into()),
+        max_on_time: Duration::from_millis(limits.max_on_time.into()),
+        cooldown: Duration::from_secs(limits.cooldown.into()),
+    })
+}
+
+pub fn arming_idle_timeout() -> anyhow::Result<Duration> {
+    default_config().map(|config| Duration::from_secs(config.arming.idle_timeout.into()))
+}
+
+// When there's an arming switch, it's the only way to arm the toy.
+pub fn arming_switch_pin() -> anyhow::Result<Option<u8>> {
+    default_config().map(|config| config.arming.switch_pin)
+}
+
+pub fn heartbeat_timeout() -> anyhow::Result<Duration> {
+    default_config().map(|config| Duration::from_secs(config.heartbeat.timeout.into()))
+}
+
+pub fn safeword() -> anyhow::Result<io::SafewordConfig> {
+    let safeword = &default_config()?.safeword;
+
+    Ok(io::SafewordConfig {
+        lockout: Duration::from_secs(safeword.lockout.into()),
+        pin: safeword.pin.clone().filter(|pin| !pin.is_empty()),
+    })
+}
+
+// How long the wearer has to answer a check-in before auto mode pauses.
+pub fn check_in_grace() -> anyhow::Result<Duration> {
+    default_config().map(|config| Duration::from_secs(config.check_in.grace.into()))
 }
 
 pub fn wifi_client_config<P: NvsPartitionId>(
@@ -425,29 +669,30 @@ pub fn wifi_client_config<P: NvsPartitionId>(
     let ssid = wifi_ssid(nvs_part.clone())?;
     let password = wifi_password(nvs_part.clone())?;
 
-    Ok(match ssid {
-        Some(ssid) if !ssid.is_empty() => Some(wifi::ClientConfiguration {
-            ssid: ssid
-                .as_str()
-                .try_into()
-                .map_err(|_| anyhow!("WiFi SSID is too long: {}", ssid))?,
-            auth_method: match &password {
-                Some(password) if !password.is_empty() => wifi::AuthMethod::default(),
-                _ => wifi::AuthMethod::None,
-            },
-            password: password
-                .as_deref()
-                .unwrap_or_default()
-                .try_into()
-                .map_err(|_| {
-                    anyhow!(
-                        "WiFi password is too long: {}",
-                        password.as_deref().unwrap_or_default()
-                    )
-                })?,
-            ..Default::default()
-        }),
-        _ => None,
+    match ssid {
+        Some(ssid) if !ssid.is_empty() => Ok(Some(client_config(
+            &ssid,
+            password.as_deref().unwrap_or_default(),
+        )?)),
+        _ => Ok(None),
+    }
+}
+
+// An empty password means the network is open.
+pub fn client_config(ssid: &str, password: &str) -> anyhow::Result<wifi::ClientConfiguration> {
+    Ok(wifi::ClientConfiguration {
+        ssid: ssid
+            .try_into()
+            .map_err(|_| anyhow!("WiFi SSID is too long: {}", ssid))?,
+        auth_method: if password.is_empty() {
+            wifi::AuthMethod::None
+        } else {
+            wifi::AuthMethod::default()
+        },
+        password: password
+            .try_into()
+            .map_err(|_| anyhow!("WiFi password is too long: {}", password))?,
+        ..Default::default()
     })
 }
 
@@ -594,3 +839,312 @@ pub fn set_freq_max<P: NvsPartitionId>(
 
     Ok(())
 }
+
+pub fn freq_distribution_kind<P: NvsPartitionId>(
+    nvs_part: EspNvsPartition<P>,
+) -> anyhow::Result<DistributionKind> {
+    let mut nvs = user_nvs(nvs_part)?;
+    let default = default_config()?;
+    let name: Option<String> = nvs.get_value("freq.dist")?;
+
+    match name {
+        Some(name) => DistributionKind::from_name(&name)
+            .ok_or_else(|| anyhow!("Invalid interval distribution: {}", name)),
+        None => Ok(default.frequency.distribution),
+    }
+}
+
+pub fn set_freq_distribution_kind<P: NvsPartitionId>(
+    nvs_part: EspNvsPartition<P>,
+    kind: DistributionKind,
+) -> anyhow::Result<()> {
+    let mut nvs = user_nvs(nvs_part)?;
+    nvs.set_str("freq.dist", kind.name())?;
+
+    Ok(())
+}
+
+// Defaults to halfway between the minimum and maximum wait.
+pub fn freq_mean<P: NvsPartitionId>(nvs_part: EspNvsPartition<P>) -> anyhow::Result<u32> {
+    let mut nvs = user_nvs(nvs_part.clone())?;
+
+    match nvs.get_value("freq.mean")? {
+        Some(mean) => Ok(mean),
+        None => Ok((freq_min(nvs_part.clone())? + freq_max(nvs_part)?) / 2),
+    }
+}
+
+pub fn set_freq_mean<P: NvsPartitionId>(
+    nvs_part: EspNvsPartition<P>,
+    mean: u32,
+) -> anyhow::Result<()> {
+    let nvs = user_nvs(nvs_part)?;
+    nvs.set_u32("freq.mean", mean)?;
+
+    Ok(())
+}
+
+// Defaults to a quarter of the range between the minimum and maximum wait.
+pub fn freq_std_dev<P: NvsPartitionId>(nvs_part: EspNvsPartition<P>) -> anyhow::Result<u32> {
+    let mut nvs = user_nvs(nvs_part.clone())?;
+
+    match nvs.get_value("freq.stddev")? {
+        Some(std_dev) => Ok(std_dev),
+        None => Ok(freq_max(nvs_part.clone())?.saturating_sub(freq_min(nvs_part)?) / 4),
+    }
+}
+
+pub fn set_freq_std_dev<P: NvsPartitionId>(
+    nvs_part: EspNvsPartition<P>,
+    std_dev: u32,
+) -> anyhow::Result<()> {
+    let nvs = user_nvs(nvs_part)?;
+    nvs.set_u32("freq.stddev", std_dev)?;
+
+    Ok(())
+}
+
+pub fn freq_skew<P: NvsPartitionId>(nvs_part: EspNvsPartition<P>) -> anyhow::Result<u32> {
+    let mut nvs = user_nvs(nvs_part)?;
+    let default = default_config()?;
+    Ok(nvs
+        .get_value("freq.skew")?
+        .unwrap_or(default.frequency.skew))
+}
+
+pub fn set_freq_skew<P: NvsPartitionId>(
+    nvs_part: EspNvsPartition<P>,
+    skew: u32,
+) -> anyhow::Result<()> {
+    let nvs = user_nvs(nvs_part)?;
+    nvs.set_u32("freq.skew", skew)?;
+
+    Ok(())
+}
+
+pub fn freq_distribution<P: NvsPartitionId>(
+    nvs_part: EspNvsPartition<P>,
+) -> anyhow::Result<IntervalDistribution> {
+    Ok(match freq_distribution_kind(nvs_part.clone())? {
+        DistributionKind::Uniform => IntervalDistribution::Uniform,
+        DistributionKind::Exponential => IntervalDistribution::Exponential {
+            mean: freq_mean(nvs_part)?.into(),
+        },
+        DistributionKind::Normal => IntervalDistribution::Normal {
+            mean: freq_mean(nvs_part.clone())?.into(),
+            std_dev: freq_std_dev(nvs_part)?.into(),
+        },
+        DistributionKind::Short => IntervalDistribution::Short {
+            skew: freq_skew(nvs_part)?.into(),
+        },
+        DistributionKind::Long => IntervalDistribution::Long {
+            skew: freq_skew(nvs_part)?.into(),
+        },
+    })
+}
+
+pub fn ui_show_countdown<P: NvsPartitionId>(nvs_part: EspNvsPartition<P>) -> anyhow::Result<bool> {
+    let mut nvs = user_nvs(nvs_part)?;
+    let default = default_config()?;
+    Ok(nvs
+        .get_value("ui.countdown")?
+        .map(|value: u32| value != 0)
+        .unwrap_or(default.ui.show_countdown))
+}
+
+pub fn set_ui_show_countdown<P: NvsPartitionId>(
+    nvs_part: EspNvsPartition<P>,
+    show_countdown: bool,
+) -> anyhow::Result<()> {
+    let nvs = user_nvs(nvs_part)?;
+    nvs.set_u32("ui.countdown", show_countdown.into())?;
+
+    Ok(())
+}
+
+// The range of intensities to randomly choose from in auto mode. If this is unset, auto mode uses
+// the default intensity.
+pub fn intensity_range<P: NvsPartitionId>(
+    nvs_part: EspNvsPartition<P>,
+) -> anyhow::Result<Option<(u8, u8)>> {
+    let mut nvs = user_nvs(nvs_part)?;
+
+    let min: Option<u32> = nvs.get_value("int.min")?;
+    let max: Option<u32> = nvs.get_value("int.max")?;
+
+    Ok(match (min, max) {
+        (Some(min), Some(max)) => Some((min.try_into()?, max.try_into()?)),
+        _ => None,
+    })
+}
+
+pub fn set_intensity_range<P: NvsPartitionId>(
+    nvs_part: EspNvsPartition<P>,
+    range: Option<(u8, u8)>,
+) -> anyhow::Result<()> {
+    let mut nvs = user_nvs(nvs_part)?;
+
+    if let Some((min, max)) = range {
+        nvs.set_u32("int.min", min.into())?;
+        nvs.set_u32("int.max", max.into())?;
+    } else {
+        nvs.remove("int.min")?;
+        nvs.remove("int.max")?;
+    }
+
+    Ok(())
+}
+
+// Patterns uploaded by the user via the API are stored in NVS as JSON, separately from the
+// built-in patterns and the ones in the config file.
+pub fn user_patterns<P: NvsPartitionId>(
+    nvs_part: EspNvsPartition<P>,
+) -> anyhow::Result<Vec<Pattern>> {
+    let mut nvs = user_nvs(nvs_part)?;
+
+    Ok(nvs
+        .get_value("io.patterns")?
+        .map(|json: Vec<u8>| serde_json::from_slice(&json))
+        .transpose()?
+        .unwrap_or_default())
+}
+
+pub fn set_user_patterns<P: NvsPartitionId>(
+    nvs_part: EspNvsPartition<P>,
+    patterns: &[Pattern],
+) -> anyhow::Result<()> {
+    let json = serde_json::to_vec(patterns)?;
+
+    if json.len() > MAX_USER_BLOB_SIZE {
+        bail!(
+            "There isn't enough room for this pattern. Patterns can't take up more than {} bytes in total.",
+            MAX_USER_BLOB_SIZE
+        );
+    }
+
+    let mut nvs = user_nvs(nvs_part)?;
+    nvs.set_blob("io.patterns", &json)?;
+
+    Ok(())
+}
+
+// All the patterns available to the user. If there are multiple patterns with the same name, user
+// patterns take precedence over patterns in the config file, which take precedence over the
+// built-in patterns.
+pub fn patterns<P: NvsPartitionId>(nvs_part: EspNvsPartition<P>) -> anyhow::Result<Vec<Pattern>> {
+    let mut patterns = io::builtin_patterns();
+
+    for pattern in default_config()?
+        .patterns
+        .iter()
+        .cloned()
+        .chain(user_patterns(nvs_part)?)
+    {
+        match patterns.iter_mut().find(|p| p.name == pattern.name) {
+            Some(existing) => *existing = pattern,
+            None => patterns.push(pattern),
+        }
+    }
+
+    Ok(patterns)
+}
+
+pub fn io_pattern<P: NvsPartitionId>(nvs_part: EspNvsPartition<P>) -> anyhow::Result<String> {
+    let mut nvs = user_nvs(nvs_part)?;
+    let default = default_config()?;
+    Ok(nvs
+        .get_value("io.pattern")?
+        .unwrap_or_else(|| default.io.pattern.clone()))
+}
+
+pub fn set_io_pattern<P: NvsPartitionId>(
+    nvs_part: EspNvsPartition<P>,
+    name: &str,
+) -> anyhow::Result<()> {
+    let mut nvs = user_nvs(nvs_part)?;
+    nvs.set_str("io.pattern", name)?;
+
+    Ok(())
+}
+
+// The pattern with the given name. If it doesn't exist (anymore), we fall back to the default
+// pattern.
+pub fn pattern<P: NvsPartitionId>(
+    nvs_part: EspNvsPartition<P>,
+    name: &str,
+) -> anyhow::Result<Pattern> {
+    let mut patterns = patterns(nvs_part)?;
+
+    if let Some(index) = patterns.iter().position(|p| p.name == name) {
+        return Ok(patterns.swap_remove(index));
+    }
+
+    log::warn!(
+        "Pattern {:?} does not exist. Using the default pattern.",
+        name
+    );
+
+    patterns
+        .into_iter()
+        .find(|p| p.name == Pattern::DEFAULT_NAME)
+        .ok_or_else(|| anyhow!("The default pattern does not exist."))
+}
+
+// The pattern the user currently has selected.
+pub fn selected_pattern<P: NvsPartitionId>(
+    nvs_part: EspNvsPartition<P>,
+) -> anyhow::Result<Pattern> {
+    let name = io_pattern(nvs_part.clone())?;
+    pattern(nvs_part, &name)
+}
+
+// Like patterns, programs uploaded by the user via the API are stored in NVS as JSON.
+pub fn user_programs<P: NvsPartitionId>(
+    nvs_part: EspNvsPartition<P>,
+) -> anyhow::Result<Vec<Program>> {
+    let mut nvs = user_nvs(nvs_part)?;
+
+    Ok(nvs
+        .get_value("io.programs")?
+        .map(|json: Vec<u8>| serde_json::from_slice(&json))
+        .transpose()?
+        .unwrap_or_default())
+}
+
+pub fn set_user_programs<P: NvsPartitionId>(
+    nvs_part: EspNvsPartition<P>,
+    programs: &[Program],
+) -> anyhow::Result<()> {
+    let json = serde_json::to_vec(programs)?;
+
+    if json.len() > MAX_USER_BLOB_SIZE {
+        bail!(
+            "There isn't enough room for this program. Programs can't take up more than {} bytes in total.",
+            MAX_USER_BLOB_SIZE
+        );
+    }
+
+    let mut nvs = user_nvs(nvs_part)?;
+    nvs.set_blob("io.programs", &json)?;
+
+    Ok(())
+}
+
+// All the programs available to the user, with the same precedence rules as `patterns`.
+pub fn programs<P: NvsPartitionId>(nvs_part: EspNvsPartition<P>) -> anyhow::Result<Vec<Program>> {
+    let mut programs = io::builtin_programs();
+
+    for program in default_config()?
+        .programs
+        .iter()
+        .cloned()
+        .chain(user_programs(nvs_part)?)
+    {
+        match programs.iter_mut().find(|p| p.name == program.name) {
+            Some(existing) => *existing = program,
+            None => programs.push(program),
+        }
+    }
+
+    Ok(programs)
+}
@@ -1,4 +1,7 @@
-use std::sync::Arc;
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use esp_idf_svc::{
     http::{
@@ -8,18 +11,26 @@ use esp_idf_svc::{
     io::Write,
     nvs::{EspNvsPartition, NvsPartitionId},
 };
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
-use crate::{config, io};
+use crate::{config, io, wifi};
 
 const HTML_INDEX: &[u8] = include_bytes!("../client/index.html");
 const HTML_SETTINGS: &[u8] = include_bytes!("../client/settings.html");
+const HTML_SAFEWORD: &[u8] = include_bytes!("../client/safeword.html");
 const CSS: &[u8] = include_bytes!("../client/index.css");
 const JS: &[u8] = include_bytes!("../client/index.js");
 const HTMX: &[u8] = include_bytes!("../client/htmx.min.js.gz");
 
 const BUF_SIZE: usize = 1024;
+// Request bodies are read into memory, and there isn't much of that to go around. User patterns
+// and programs are the largest things clients send us, and they fit comfortably in this.
+const MAX_BODY_SIZE: usize = 4096;
+const MAX_USER_PATTERNS: usize = 16;
+const MAX_USER_PROGRAMS: usize = 16;
 const HTTP_SERVER_STACK_SIZE: usize = 20480;
+// The default limit is 32, which we've outgrown.
+const MAX_URI_HANDLERS: usize = 64;
 
 fn html_resp<C>(req: Request<C>, status: u16, body: impl AsRef<[u8]>) -> anyhow::Result<()>
 where
@@ -32,25 +43,261 @@ where
     Ok(())
 }
 
+// Respond with an HTML fragment and have HTMX fire an event on the page, so that other elements
+// which depend on this change can refresh themselves.
+fn html_trigger_resp<C>(req: Request<C>, event: &str, body: impl AsRef<[u8]>) -> anyhow::Result<()>
+where
+    C: Connection,
+    C::Error: std::error::Error + Send + Sync + 'static,
+{
+    req.into_response(
+        200,
+        None,
+        &[("Content-Type", "text/html"), ("HX-Trigger", event)],
+    )?
+    .write_all(body.as_ref())?;
+
+    Ok(())
+}
+
+fn json_resp<C>(req: Request<C>, status: u16, body: &impl Serialize) -> anyhow::Result<()>
+where
+    C: Connection,
+    C::Error: std::error::Error + Send + Sync + 'static,
+{
+    req.into_response(status, None, &[("Content-Type", "application/json")])?
+        .write_all(&serde_json::to_vec(body)?)?;
+
+    Ok(())
+}
+
+fn json_error_resp<C>(req: Request<C>, status: u16, err: impl ToString) -> anyhow::Result<()>
+where
+    C: Connection,
+    C::Error: std::error::Error + Send + Sync + 'static,
+{
+    json_resp(
+        req,
+        status,
+        &serde_json::json!({ "error": err.to_string() }),
+    )
+}
+
+// API clients can ask for JSON instead of an HTML fragment.
+fn wants_json<C: Connection>(req: &Request<C>) -> bool {
+    req.header("Accept")
+        .is_some_and(|accept| accept.contains("application/json"))
+}
+
+// Anything that didn't come from us, like the name of a nearby network, has to be escaped before
+// it goes in HTML.
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+// If the body is larger than `MAX_BODY_SIZE`, this sends a 413 response itself and returns an
+// error, so the handler only has to bail out.
 fn read_body<C>(req: &mut Request<C>) -> anyhow::Result<Vec<u8>>
 where
     C: Connection,
     C::Error: std::error::Error + Send + Sync + 'static,
 {
+    // If the client tells us how large the body is up front, we don't have to read any of it.
+    let mut is_too_large = req
+        .header("Content-Length")
+        .and_then(|len| len.parse::<usize>().ok())
+        .is_some_and(|len| len > MAX_BODY_SIZE);
+
     let mut body = Vec::new();
     let mut buf = vec![0; BUF_SIZE];
 
-    while let Ok(len) = req.read(&mut buf) {
+    while !is_too_large {
+        let len = req.read(&mut buf)?;
+
         if len == 0 {
             break;
         }
 
+        if body.len() + len > MAX_BODY_SIZE {
+            is_too_large = true;
+            break;
+        }
+
         body.extend_from_slice(&buf[..len]);
     }
 
+    if is_too_large {
+        let message = format!("Requests can't be larger than {} bytes.", MAX_BODY_SIZE);
+
+        let (content_type, resp_body) = if wants_json(req) {
+            (
+                "application/json",
+                serde_json::to_vec(&serde_json::json!({ "error": message }))?,
+            )
+        } else {
+            (
+                "text/html",
+                format!(r#"<p role="alert">{}</p>"#, message).into_bytes(),
+            )
+        };
+
+        req.connection()
+            .initiate_response(413, None, &[("Content-Type", content_type)])?;
+        req.connection().write_all(&resp_body)?;
+
+        anyhow::bail!(message);
+    }
+
     Ok(body)
 }
 
+const AUTO_CHANGED_EVENT: &str = "auto-changed";
+
+fn auto_button(is_auto: bool) -> String {
+    format!(
+        r##"
+        <button
+          id="auto-button"
+          role="switch"
+          aria-checked="{is_auto}"
+          hx-post="{endpoint}"
+          hx-include="#session-select, #check-in-select"
+          hx-swap="outerHTML"
+        >
+          AUTO
+        </button>
+        "##,
+        is_auto = is_auto,
+        endpoint = if is_auto { "/api/stop" } else { "/api/start" },
+    )
+}
+
+// The pause button is only usable while auto mode is on.
+fn pause_button(is_auto: bool, is_paused: bool) -> String {
+    format!(
+        r#"
+        <button
+          id="pause-button"
+          role="switch"
+          aria-checked="{is_paused}"
+          hx-post="{endpoint}"
+          hx-swap="outerHTML"
+          {disabled}
+        >
+          PAUSE
+        </button>
+        "#,
+        is_paused = is_paused,
+        endpoint = if is_paused {
+            "/api/resume"
+        } else {
+            "/api/pause"
+        },
+        disabled = if is_auto { "" } else { "disabled" },
+    )
+}
+
+fn format_countdown(seconds: u64) -> String {
+    format!("{}:{:02}", seconds / 60, seconds % 60)
+}
+
+// Program names are validated to only contain characters that are safe to include in HTML without
+// escaping.
+fn program_form(programs: &[io::Program]) -> String {
+    let options = programs
+        .iter()
+        .map(|program| {
+            format!(
+                r#"<option value="{name}">{name}</option>"#,
+                name = program.name
+            )
+        })
+        .collect::<String>();
+
+    format!(
+        r##"
+        <form
+          id="program-form"
+          class="picker"
+          hx-post="/api/program/start"
+          hx-include="#check-in-select"
+          hx-target="#program-control"
+        >
+          <label for="program-select">Program</label>
+          <select id="program-select" name="program">
+            {options}
+          </select>
+          <button id="program-button" type="submit">START</button>
+        </form>
+        "##,
+        options = options,
+    )
+}
+
+// This polls for updates for as long as the program is running. Once it's over, the response
+// swaps in the form to start another one.
+fn program_status(status: &io::ProgramStatus, is_paused: bool) -> String {
+    format!(
+        r##"
+        <div
+          id="program-status"
+          hx-get="/api/program"
+          hx-trigger="every 5s"
+          hx-target="#program-control"
+        >
+          <p>Program <strong>{name}</strong>{paused}</p>
+          <p>Phase {phase} of {phases}: {phase_remaining} left ({remaining} total)</p>
+        </div>
+        <button
+          id="program-button"
+          hx-post="/api/program/stop"
+          hx-target="#program-control"
+        >
+          STOP
+        </button>
+        "##,
+        name = status.name,
+        paused = if is_paused { " (paused)" } else { "" },
+        phase = status.phase + 1,
+        phases = status.phases,
+        phase_remaining = format_countdown(status.phase_remaining.as_secs()),
+        remaining = format_countdown(status.remaining.as_secs()),
+    )
+}
+
+#[derive(Debug, Serialize)]
+struct ProgramStatusBody {
+    name: String,
+    // Starting at 1.
+    phase: usize,
+    phases: usize,
+    phase_seconds: u64,
+    seconds: u64,
+    paused: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct NextAutoFireBody {
+    auto: bool,
+    paused: bool,
+    seconds: Option<u64>,
+    // How long until the auto mode session ends, if it has an end.
+    session_seconds: Option<u64>,
+}
+
 #[derive(Debug, Deserialize)]
 struct WifiSettingsFormBody {
     ssid: String,
@@ -58,24 +305,24 @@ struct WifiSettingsFormBody {
 }
 
 impl WifiSettingsFormBody {
-    fn save<P: NvsPartitionId>(&self, nvs_part: EspNvsPartition<P>) -> anyhow::Result<()> {
-        config::set_wifi_ssid(
-            nvs_part.clone(),
-            if self.ssid.trim().is_empty() {
-                None
-            } else {
-                Some(&self.ssid)
-            },
-        )?;
+    fn ssid(&self) -> Option<&str> {
+        if self.ssid.trim().is_empty() {
+            None
+        } else {
+            Some(&self.ssid)
+        }
+    }
 
-        config::set_wifi_password(
-            nvs_part.clone(),
-            if self.password.trim().is_empty() {
-                None
-            } else {
-                Some(&self.password)
-            },
-        )?;
+    fn password(&self) -> Option<&str> {
+        if self.password.trim().is_empty() {
+            None
+        } else {
+            Some(&self.password)
+        }
+    }
+
+    fn save<P: NvsPartitionId>(&self, nvs_part: EspNvsPartition<P>) -> anyhow::Result<()> {
+        config::set_wifi_credentials(nvs_part, self.ssid(), self.password())?;
 
         log::info!("WiFi settings saved.");
 
@@ -83,206 +330,1812 @@ impl WifiSettingsFormBody {
     }
 }
 
-#[derive(Debug, Deserialize)]
-struct FreqSettingsFormBody {
-    min_freq: u32,
-    max_freq: u32,
+#[derive(Debug, Serialize)]
+struct WifiFailureBody {
+    failure: Option<&'static str>,
+    message: Option<String>,
 }
 
-impl FreqSettingsFormBody {
-    fn save<P: NvsPartitionId>(&self, nvs_part: EspNvsPartition<P>) -> anyhow::Result<()> {
-        config::set_freq_min(nvs_part.clone(), self.min_freq)?;
-        config::set_freq_max(nvs_part.clone(), self.max_freq)?;
+#[derive(Debug, Serialize)]
+struct CredentialTestBody {
+    state: &'static str,
+    failure: Option<&'static str>,
+    message: Option<String>,
+}
 
-        log::info!("Frequency settings saved.");
+impl From<wifi::CredentialTest> for CredentialTestBody {
+    fn from(test: wifi::CredentialTest) -> Self {
+        match test {
+            wifi::CredentialTest::Idle => Self {
+                state: "idle",
+                failure: None,
+                message: None,
+            },
+            wifi::CredentialTest::Pending => Self {
+                state: "pending",
+                failure: None,
+                message: None,
+            },
+            wifi::CredentialTest::Passed => Self {
+                state: "passed",
+                failure: None,
+                message: None,
+            },
+            wifi::CredentialTest::Failed(failure) => Self {
+                state: "failed",
+                failure: Some(failure.code()),
+                message: Some(failure.to_string()),
+            },
+            wifi::CredentialTest::Error => Self {
+                state: "error",
+                failure: None,
+                message: None,
+            },
+        }
+    }
+}
 
-        Ok(())
+// These messages never contain user input, so they're safe to include in HTML without escaping.
+// While the test is on the way, this polls until it's done.
+fn credential_test_result(test: wifi::CredentialTest) -> String {
+    match test {
+        wifi::CredentialTest::Idle => String::new(),
+        wifi::CredentialTest::Pending => String::from(
+            r#"
+            <div
+              hx-get="/api/settings/wifi/test"
+              hx-trigger="load delay:1s"
+              hx-target="this"
+              hx-swap="outerHTML"
+              hx-confirm="unset"
+            >
+              <p>Testing the new WiFi settings...</p>
+            </div>
+            "#,
+        ),
+        wifi::CredentialTest::Passed => String::from(
+            "<p>The toy connected, so the WiFi settings were saved. It's switching to the new network now.</p>",
+        ),
+        wifi::CredentialTest::Failed(failure) => format!(
+            r#"<p role="alert">The toy couldn't connect, so the WiFi settings weren't saved. {}</p>"#,
+            failure
+        ),
+        wifi::CredentialTest::Error => String::from(
+            r#"<p role="alert">Something went wrong testing the WiFi settings, so they weren't saved. Try again.</p>"#,
+        ),
     }
 }
 
-pub fn serve<P>(
+// WiFi settings are only saved once the toy has managed to connect with them, so that a typo can't
+// cut it off from the local network. Testing them means trying to connect, which can take a while,
+// so we don't hold up the HTTP server waiting for it. Clients should poll
+// `GET /api/settings/wifi/test` for the result.
+fn save_wifi_settings<C, P>(
+    mut req: Request<C>,
     nvs_part: EspNvsPartition<P>,
-    signaler: Arc<io::Signaler>,
-) -> anyhow::Result<EspHttpServer<'static>>
+    wifi_handle: &wifi::WifiHandle,
+) -> anyhow::Result<()>
 where
-    P: NvsPartitionId + Send + Sync + 'static,
+    C: Connection,
+    C::Error: std::error::Error + Send + Sync + 'static,
+    P: NvsPartitionId,
 {
-    let server_config = Configuration {
-        http_port: config::http_port()?,
-        stack_size: HTTP_SERVER_STACK_SIZE,
-        ..Default::default()
-    };
+    let req_body = read_body(&mut req)?;
+    let form_body = serde_urlencoded::from_bytes::<WifiSettingsFormBody>(&req_body)?;
 
-    let mut server = EspHttpServer::new(&server_config)?;
+    // Without a network, there's nothing to test. This is how the user stops the toy from
+    // connecting to the local network.
+    let Some(ssid) = form_body.ssid() else {
+        form_body.save(nvs_part)?;
+        wifi_handle.apply_settings()?;
 
-    //
-    // Static assets
-    //
+        if wants_json(&req) {
+            return json_resp(
+                req,
+                200,
+                &CredentialTestBody::from(wifi::CredentialTest::Passed),
+            );
+        }
 
-    server.fn_handler(
-        "/assets/index.css",
-        Method::Get,
-        |req| -> anyhow::Result<()> {
-            let headers = [("Content-Type", "text/css")];
+        return html_resp(
+            req,
+            200,
+            "<p>WiFi settings saved. The toy is only using its hotspot now.</p>",
+        );
+    };
 
-            let mut resp = req.into_response(200, None, &headers)?;
-            resp.write_all(CSS)?;
+    if let Err(err) = wifi_handle.test_credentials(ssid, form_body.password().unwrap_or_default()) {
+        if wants_json(&req) {
+            return json_error_resp(req, 409, err);
+        }
 
-            Ok(())
-        },
-    )?;
+        return html_resp(req, 409, format!(r#"<p role="alert">{}</p>"#, err));
+    }
 
-    server.fn_handler(
-        "/assets/index.js",
-        Method::Get,
-        |req| -> anyhow::Result<()> {
-            let headers = [("Content-Type", "application/javascript")];
+    let test = wifi_handle.status().credential_test();
 
-            let mut resp = req.into_response(200, None, &headers)?;
-            resp.write_all(JS)?;
+    if wants_json(&req) {
+        return json_resp(req, 202, &CredentialTestBody::from(test));
+    }
 
-            Ok(())
-        },
-    )?;
+    html_resp(req, 200, credential_test_result(test))
+}
 
-    server.fn_handler(
-        "/assets/htmx.min.js",
-        Method::Get,
-        |req| -> anyhow::Result<()> {
-            let headers = [
-                ("Content-Type", "text/javascript"),
-                ("Content-Encoding", "gzip"),
-            ];
+#[derive(Debug, Deserialize)]
+struct FreqSettingsFormBody {
+    min_freq: u32,
+    max_freq: u32,
+    // These are loaded into the form separately, so they may be missing if that request failed.
+    distribution: Option<String>,
+    mean: Option<u32>,
+    std_dev: Option<u32>,
+    skew: Option<u32>,
+}
 
-            let mut resp = req.into_response(200, None, &headers)?;
-            resp.write_all(HTMX)?;
+impl FreqSettingsFormBody {
+    const MAX_SKEW: u32 = 5;
 
-            Ok(())
-        },
-    )?;
+    fn save<P: NvsPartitionId>(&self, nvs_part: EspNvsPartition<P>) -> anyhow::Result<()> {
+        config::set_freq_min(nvs_part.clone(), self.min_freq)?;
+        config::set_freq_max(nvs_part.clone(), self.max_freq)?;
 
-    //
-    // HTML pages
-    //
+        if let Some(name) = &self.distribution {
+            let kind = io::DistributionKind::from_name(name)
+                .ok_or_else(|| anyhow::anyhow!("Invalid interval distribution: {}", name))?;
 
-    server.fn_handler("/", Method::Get, |req| -> anyhow::Result<()> {
-        html_resp(req, 200, HTML_INDEX)
-    })?;
+            config::set_freq_distribution_kind(nvs_part.clone(), kind)?;
+        }
 
-    server.fn_handler("/settings", Method::Get, |req| -> anyhow::Result<()> {
-        html_resp(req, 200, HTML_SETTINGS)
-    })?;
+        if let Some(mean) = self.mean {
+            config::set_freq_mean(nvs_part.clone(), mean)?;
+        }
 
-    //
-    // API endpoints
-    //
+        if let Some(std_dev) = self.std_dev {
+            config::set_freq_std_dev(nvs_part.clone(), std_dev)?;
+        }
 
-    let this_signaler = Arc::clone(&signaler);
+        if let Some(skew) = self.skew {
+            config::set_freq_skew(nvs_part.clone(), skew.clamp(1, Self::MAX_SKEW))?;
+        }
 
-    server.fn_handler(
-        "/api/fire",
-        Method::Post,
-        move |req| -> anyhow::Result<()> {
-            this_signaler.send(io::Signal::Fire);
+        log::info!("Frequency settings saved.");
 
-            req.into_ok_response()?;
+        Ok(())
+    }
+}
 
-            Ok(())
-        },
-    )?;
+#[derive(Debug, Deserialize)]
+struct UiSettingsFormBody {
+    // This is a checkbox, so it's only present when it's checked.
+    show_countdown: Option<String>,
+}
 
-    let this_signaler = Arc::clone(&signaler);
+impl UiSettingsFormBody {
+    fn save<P: NvsPartitionId>(&self, nvs_part: EspNvsPartition<P>) -> anyhow::Result<()> {
+        config::set_ui_show_countdown(nvs_part, self.show_countdown.is_some())?;
 
-    server.fn_handler(
-        "/api/start",
-        Method::Post,
-        move |req| -> anyhow::Result<()> {
-            this_signaler.send(io::Signal::StartAuto);
+        log::info!("UI settings saved.");
 
-            html_resp(
-                req,
-                200,
-                r#"
-                <button
-                  id="auto-button"
-                  role="switch"
-                  aria-checked="true"
-                  hx-post="/api/stop"
-                  hx-swap="outerHTML"
-                >
-                  AUTO
-                </button>
-                "#,
+        Ok(())
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct IntensitySettingsFormBody {
+    // This is a checkbox, so it's only present when it's checked.
+    random_intensity: Option<String>,
+    min_intensity: u8,
+    max_intensity: u8,
+}
+
+impl IntensitySettingsFormBody {
+    fn save<P: NvsPartitionId>(&self, nvs_part: EspNvsPartition<P>) -> anyhow::Result<()> {
+        let range = self.random_intensity.as_ref().map(|_| {
+            (
+                self.min_intensity.min(self.max_intensity),
+                self.min_intensity.max(self.max_intensity),
             )
-        },
-    )?;
+        });
 
-    let this_signaler = Arc::clone(&signaler);
+        config::set_intensity_range(nvs_part, range)?;
 
-    server.fn_handler(
-        "/api/stop",
-        Method::Post,
-        move |req| -> anyhow::Result<()> {
-            this_signaler.send(io::Signal::StopAuto);
+        log::info!("Intensity settings saved.");
 
-            html_resp(
-                req,
-                200,
-                r#"
-                <button
-                  id="auto-button"
-                  role="switch"
-                  aria-checked="false"
-                  hx-post="/api/start"
-                  hx-swap="outerHTML"
-                >
-                  AUTO
-                </button>
-                "#,
-            )
-        },
-    )?;
+        Ok(())
+    }
+}
 
-    let this_signaler = Arc::clone(&signaler);
+#[derive(Debug, Deserialize)]
+struct FireFormBody {
+    intensity: Option<u8>,
+    // In milliseconds.
+    duration: Option<u32>,
+}
 
-    server.fn_handler("/api/auto", Method::Get, move |req| -> anyhow::Result<()> {
-        let is_auto = this_signaler.is_auto();
+impl FireFormBody {
+    // Don't let API clients run the pump for longer than a pattern could.
+    const MAX_DURATION: u32 = 60_000;
+
+    fn params(&self) -> io::FireParams {
+        io::FireParams {
+            intensity: self.intensity,
+            duration: self
+                .duration
+                .map(|ms| Duration::from_millis(ms.min(Self::MAX_DURATION).into())),
+            pattern: None,
+        }
+    }
+}
 
-        let endpoint = if is_auto { "/api/stop" } else { "/api/start" };
+#[derive(Debug, Deserialize)]
+struct HoldFormBody {
+    intensity: Option<u8>,
+}
 
-        html_resp(
-            req,
-            200,
-            format!(
-                r#"
-                    <button
-                      id="auto-button"
-                      role="switch"
-                      aria-checked="{is_auto}"
-                      hx-post="{endpoint}"
-                      hx-swap="outerHTML"
-                    >
-                      AUTO
-                    </button>
-                    "#,
-                is_auto = is_auto,
-                endpoint = endpoint,
-            ),
-        )
-    })?;
+#[derive(Debug, Deserialize)]
+struct HoldUpdateFormBody {
+    id: u32,
+}
 
-    let this_nvs_part = nvs_part.clone();
+#[derive(Debug, Serialize)]
+struct HoldBody {
+    id: u32,
+}
 
-    server.fn_handler("/api/addr", Method::Get, move |req| -> anyhow::Result<()> {
-        let addr = config::wifi_ip_addr(this_nvs_part.clone())?;
+// Session lengths and check-in intervals are both capped at a day.
+const MAX_FORM_SECONDS: u32 = 24 * 60 * 60;
 
-        html_resp(
-            req,
-            200,
-            &match addr {
-                Some(addr) => format!(
-                    "
-                    <p>Your Squirtinator is connected to WiFi.</p>
+// Zero and missing both mean "never".
+fn form_seconds(seconds: Option<u32>) -> Option<Duration> {
+    seconds
+        .filter(|&seconds| seconds > 0)
+        .map(|seconds| Duration::from_secs(seconds.min(MAX_FORM_SECONDS).into()))
+}
+
+#[derive(Debug, Deserialize)]
+struct AutoFormBody {
+    // In seconds. Zero means auto mode runs until the user stops it.
+    session: Option<u32>,
+    // In seconds. Zero means the wearer is never asked to check in.
+    check_in: Option<u32>,
+}
+
+impl AutoFormBody {
+    fn session(&self) -> Option<Duration> {
+        form_seconds(self.session)
+    }
+
+    fn check_in(&self) -> Option<Duration> {
+        form_seconds(self.check_in)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ScheduleFormBody {
+    // In seconds.
+    delay: u32,
+    intensity: Option<u8>,
+    // In milliseconds.
+    duration: Option<u32>,
+}
+
+impl ScheduleFormBody {
+    const MAX_DELAY: u32 = 24 * 60 * 60;
+
+    fn delay(&self) -> Duration {
+        Duration::from_secs(self.delay.min(Self::MAX_DELAY).into())
+    }
+
+    fn params(&self) -> io::FireParams {
+        FireFormBody {
+            intensity: self.intensity,
+            duration: self.duration,
+        }
+        .params()
+    }
+}
+
+// The schedule form swaps in the timer list, so errors have to be sent somewhere else.
+fn schedule_error_resp<C>(req: Request<C>, status: u16, err: impl ToString) -> anyhow::Result<()>
+where
+    C: Connection,
+    C::Error: std::error::Error + Send + Sync + 'static,
+{
+    if wants_json(&req) {
+        return json_error_resp(req, status, err);
+    }
+
+    req.into_response(
+        status,
+        None,
+        &[
+            ("Content-Type", "text/html"),
+            ("HX-Retarget", "#timer-feedback"),
+        ],
+    )?
+    .write_all(format!(r#"<p role="alert">{}</p>"#, escape_html(&err.to_string())).as_bytes())?;
+
+    Ok(())
+}
+
+#[derive(Debug, Deserialize)]
+struct CancelTimerFormBody {
+    id: u32,
+}
+
+#[derive(Debug, Serialize)]
+struct TimerBody {
+    id: u32,
+    seconds: u64,
+}
+
+impl From<io::PendingTimer> for TimerBody {
+    fn from(timer: io::PendingTimer) -> Self {
+        Self {
+            id: timer.id,
+            seconds: timer.remaining.as_secs(),
+        }
+    }
+}
+
+// The toy is disarmed when it boots, and it can disarm itself, so the remote always shows which
+// it is. If the toy has an arming switch, that's the only way to arm it.
+fn arm_control(is_armed: bool, has_switch: bool) -> String {
+    let button = match (is_armed, has_switch) {
+        (true, _) => {
+            r##"
+            <button id="arm-button" hx-post="/api/disarm" hx-target="#arm-control">
+              DISARM
+            </button>
+            "##
+        }
+        (false, false) => {
+            r##"
+            <button
+              id="arm-button"
+              hx-post="/api/arm"
+              hx-target="#arm-control"
+              hx-confirm="Arm the toy? It will be able to squirt."
+            >
+              ARM
+            </button>
+            "##
+        }
+        (false, true) => "<p>Use the switch on the toy to arm it.</p>",
+    };
+
+    format!(
+        r#"
+        <p id="arm-status" class="{class}" role="status">{status}</p>
+        {button}
+        "#,
+        class = if is_armed { "armed" } else { "disarmed" },
+        status = if is_armed { "Armed" } else { "Disarmed" },
+        button = button,
+    )
+}
+
+// Every page shows this while the safeword lockout is on, so that whoever is controlling the toy
+// knows why nothing works.
+fn lockout_notice(lockout: Option<Duration>) -> String {
+    match lockout {
+        Some(remaining) => format!(
+            r#"
+            <p id="lockout-notice" role="alert">
+              The safeword has been used. Everything is locked for {remaining}.
+            </p>
+            "#,
+            remaining = format_countdown(remaining.as_secs()),
+        ),
+        None => String::new(),
+    }
+}
+
+// This is polled, so it only shows up while the wearer is being asked to check in.
+fn check_in_prompt(remaining: Option<Duration>) -> String {
+    match remaining {
+        Some(remaining) => format!(
+            r##"
+            <div id="check-in-prompt" role="alertdialog" aria-labelledby="check-in-message">
+              <p id="check-in-message">
+                Do you want to keep going? Auto mode pauses in {remaining}.
+              </p>
+              <button
+                id="check-in-button"
+                hx-post="/api/check-in"
+                hx-target="#check-in-control"
+              >
+                KEEP GOING
+              </button>
+            </div>
+            "##,
+            remaining = format_countdown(remaining.as_secs()),
+        ),
+        None => String::new(),
+    }
+}
+
+// Shown when the page loads after the toy gave up on the remote, until the user dismisses it.
+fn heartbeat_notice(lost: Option<Duration>) -> String {
+    match lost {
+        Some(ago) => format!(
+            r##"
+            <div id="heartbeat-notice" role="alert">
+              <p>
+                The toy lost contact with the remote {ago} ago, so it stopped auto mode and the
+                pump.
+              </p>
+              <button hx-post="/api/heartbeat/dismiss" hx-target="#heartbeat-control">OK</button>
+            </div>
+            "##,
+            ago = format_countdown(ago.as_secs()),
+        ),
+        None => String::new(),
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct HeartbeatLostBody {
+    lost: bool,
+    seconds: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+struct CheckInBody {
+    pending: bool,
+    seconds: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+struct LockoutBody {
+    locked: bool,
+    seconds: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct LiftLockoutFormBody {
+    pin: String,
+}
+
+// How the remote tells the user what happened when they pressed the button.
+#[derive(Debug)]
+struct FireFeedback {
+    status: u16,
+    outcome: &'static str,
+    message: String,
+    retry_seconds: Option<u64>,
+}
+
+impl From<io::Outcome> for FireFeedback {
+    fn from(outcome: io::Outcome) -> Self {
+        match outcome {
+            io::Outcome::Accepted => Self {
+                status: 200,
+                outcome: "accepted",
+                message: String::from("Squirt!"),
+                retry_seconds: None,
+            },
+            io::Outcome::TestMode => Self {
+                status: 202,
+                outcome: "test-mode",
+                message: String::from("Test mode. No pump is connected."),
+                retry_seconds: None,
+            },
+            io::Outcome::Busy => Self {
+                status: 409,
+                outcome: "busy",
+                message: String::from("The toy is already active."),
+                retry_seconds: None,
+            },
+            io::Outcome::RateLimited(limited) => Self {
+                status: 429,
+                outcome: "rate-limited",
+                message: limited.to_string(),
+                retry_seconds: Some(limited.retry_after.as_secs()),
+            },
+            io::Outcome::Disarmed => Self {
+                status: 423,
+                outcome: "disarmed",
+                message: String::from("The toy is disarmed. Arm it first."),
+                retry_seconds: None,
+            },
+            io::Outcome::LockedOut(remaining) => Self {
+                status: 403,
+                outcome: "locked-out",
+                message: format!(
+                    "The safeword has been used. Everything is locked for {}.",
+                    format_countdown(remaining.as_secs())
+                ),
+                retry_seconds: Some(remaining.as_secs()),
+            },
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct FireBody<'a> {
+    outcome: &'a str,
+    message: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    retry_seconds: Option<u64>,
+}
+
+impl<'a> From<&'a FireFeedback> for FireBody<'a> {
+    fn from(feedback: &'a FireFeedback) -> Self {
+        Self {
+            outcome: feedback.outcome,
+            message: &feedback.message,
+            retry_seconds: feedback.retry_seconds,
+        }
+    }
+}
+
+// The messages here never contain user input, so they're safe to include in HTML without
+// escaping.
+fn fire_feedback(feedback: &FireFeedback) -> String {
+    format!(
+        r#"<p class="fire-feedback fire-feedback-{outcome}" role="status">{message}</p>"#,
+        outcome = feedback.outcome,
+        message = feedback.message,
+    )
+}
+
+#[derive(Debug, Serialize)]
+struct OutcomeCountsBody {
+    accepted: u32,
+    busy: u32,
+    rate_limited: u32,
+    disarmed: u32,
+    locked_out: u32,
+    test_mode: u32,
+    pump_errors: u32,
+}
+
+impl From<io::OutcomeCounts> for OutcomeCountsBody {
+    fn from(counts: io::OutcomeCounts) -> Self {
+        Self {
+            accepted: counts.accepted,
+            busy: counts.busy,
+            rate_limited: counts.rate_limited,
+            disarmed: counts.disarmed,
+            locked_out: counts.locked_out,
+            test_mode: counts.test_mode,
+            pump_errors: counts.pump_errors,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct StateBody {
+    state: io::State,
+    armed: bool,
+    // How long until the safeword lockout ends, if there is one.
+    lockout_seconds: Option<u64>,
+    // How long until the toy disarms itself for being idle, if it's counting down.
+    idle_seconds: Option<u64>,
+    auto: bool,
+    paused: bool,
+    firing: bool,
+    // How long until the toy can fire again, if it's cooling down.
+    cooldown_seconds: Option<u64>,
+}
+
+impl From<io::Snapshot> for StateBody {
+    fn from(snapshot: io::Snapshot) -> Self {
+        Self {
+            state: snapshot.state,
+            armed: snapshot.is_armed,
+            lockout_seconds: snapshot.lockout.map(|lockout| lockout.as_secs()),
+            idle_seconds: snapshot.idle_remaining.map(|idle| idle.as_secs()),
+            auto: snapshot.is_auto,
+            paused: snapshot.is_paused,
+            firing: snapshot.is_firing,
+            cooldown_seconds: snapshot.cooldown.map(|cooldown| cooldown.as_secs()),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct DiagnosticsBody {
+    // What has happened to every request to run the pump since the device booted.
+    outcomes: OutcomeCountsBody,
+    // How many devices are connected to the toy's access point.
+    stations: u32,
+    wifi: ConnectionBody,
+}
+
+// Where the toy is in connecting to the local network.
+#[derive(Debug, Serialize)]
+struct ConnectionBody {
+    state: &'static str,
+    attempt: Option<u32>,
+    retry_seconds: Option<u64>,
+    backoff_seconds: Option<u64>,
+    failure: Option<&'static str>,
+}
+
+impl From<wifi::Connection> for ConnectionBody {
+    fn from(connection: wifi::Connection) -> Self {
+        match connection {
+            wifi::Connection::Disconnected => Self {
+                state: "disconnected",
+                attempt: None,
+                retry_seconds: None,
+                backoff_seconds: None,
+                failure: None,
+            },
+            wifi::Connection::Connecting { attempt } => Self {
+                state: "connecting",
+                attempt: Some(attempt),
+                retry_seconds: None,
+                backoff_seconds: None,
+                failure: None,
+            },
+            wifi::Connection::BackingOff {
+                attempt,
+                delay,
+                until,
+            } => Self {
+                state: "backing_off",
+                attempt: Some(attempt),
+                retry_seconds: Some(until.saturating_duration_since(Instant::now()).as_secs()),
+                backoff_seconds: Some(delay.as_secs()),
+                failure: None,
+            },
+            wifi::Connection::Connected => Self {
+                state: "connected",
+                attempt: None,
+                retry_seconds: None,
+                backoff_seconds: None,
+                failure: None,
+            },
+            wifi::Connection::Failed(failure) => Self {
+                state: "failed",
+                attempt: None,
+                retry_seconds: None,
+                backoff_seconds: None,
+                failure: Some(failure.code()),
+            },
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct AttemptBody {
+    seconds_ago: u64,
+    failure: Option<&'static str>,
+}
+
+#[derive(Debug, Serialize)]
+struct RssiSampleBody {
+    seconds_ago: u64,
+    rssi: i8,
+}
+
+// Everything we know about the toy's connection to the local network.
+#[derive(Debug, Serialize)]
+struct WifiStatusBody {
+    connection: ConnectionBody,
+    // The toy couldn't switch to the saved settings, so it's still using the ones from before.
+    settings_failed: bool,
+    last_failure: Option<&'static str>,
+    bssid: Option<String>,
+    channel: Option<u8>,
+    // The oldest come first.
+    attempts: Vec<AttemptBody>,
+    rssi_samples: Vec<RssiSampleBody>,
+    // How many devices are connected to the toy's access point.
+    stations: u32,
+}
+
+impl From<&wifi::WifiStatus> for WifiStatusBody {
+    fn from(status: &wifi::WifiStatus) -> Self {
+        let link = status.link();
+
+        Self {
+            connection: status.connection().into(),
+            settings_failed: status.settings_failed(),
+            last_failure: status.last_failure().map(|failure| failure.code()),
+            bssid: link.map(|link| format_bssid(&link.bssid)),
+            channel: link.map(|link| link.channel),
+            attempts: status
+                .attempts()
+                .into_iter()
+                .map(|attempt| AttemptBody {
+                    seconds_ago: attempt.at.elapsed().as_secs(),
+                    failure: attempt.failure.map(|failure| failure.code()),
+                })
+                .collect(),
+            rssi_samples: status
+                .rssi_samples()
+                .into_iter()
+                .map(|sample| RssiSampleBody {
+                    seconds_ago: sample.at.elapsed().as_secs(),
+                    rssi: sample.rssi,
+                })
+                .collect(),
+            stations: status.stations(),
+        }
+    }
+}
+
+fn format_bssid(bssid: &[u8; 6]) -> String {
+    bssid
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+fn connection_description(connection: wifi::Connection) -> String {
+    match connection {
+        wifi::Connection::Disconnected => String::from("Not connected"),
+        wifi::Connection::Connecting { attempt } => format!("Connecting (attempt {})", attempt),
+        wifi::Connection::BackingOff {
+            attempt,
+            delay,
+            until,
+        } => format!(
+            "Backing off for {}s, trying again in {}s (attempt {})",
+            delay.as_secs(),
+            until.saturating_duration_since(Instant::now()).as_secs(),
+            attempt,
+        ),
+        wifi::Connection::Connected => String::from("Connected"),
+        wifi::Connection::Failed(_) => String::from("Gave up, only using the hotspot"),
+    }
+}
+
+// None of this contains user input, so it's safe to include in HTML without escaping. The SSID is
+// deliberately left out for that reason.
+fn wifi_status_panel(status: &wifi::WifiStatus) -> String {
+    let link = status.link();
+    let samples = status.rssi_samples();
+
+    let attempts = status
+        .attempts()
+        .iter()
+        .rev()
+        .map(|attempt| {
+            format!(
+                "<li>{}s ago: {}</li>",
+                attempt.at.elapsed().as_secs(),
+                match attempt.failure {
+                    Some(failure) => failure.to_string(),
+                    None => String::from("Connected."),
+                },
+            )
+        })
+        .collect::<String>();
+
+    let settings_failed = if status.settings_failed() {
+        r#"<p role="alert">The toy couldn't switch to the new WiFi settings, so it's still using the old ones. Try saving them again.</p>"#
+    } else {
+        ""
+    };
+
+    format!(
+        r#"
+        {settings_failed}
+        <dl>
+          <dt>Status</dt>
+          <dd>{connection}</dd>
+          <dt>Access point</dt>
+          <dd>{bssid}</dd>
+          <dt>Channel</dt>
+          <dd>{channel}</dd>
+          <dt>Signal</dt>
+          <dd>{signal}</dd>
+          <dt>Devices on the hotspot</dt>
+          <dd>{stations}</dd>
+        </dl>
+        <ul id="wifi-attempts">{attempts}</ul>
+        "#,
+        connection = connection_description(status.connection()),
+        bssid = link.map_or_else(|| String::from("None"), |link| format_bssid(&link.bssid)),
+        channel = link.map_or_else(|| String::from("None"), |link| link.channel.to_string()),
+        signal = match samples.last() {
+            Some(sample) => format!(
+                "{} dBm (recently {} dBm)",
+                sample.rssi,
+                samples
+                    .iter()
+                    .rev()
+                    .take(6)
+                    .map(|sample| sample.rssi.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", "),
+            ),
+            None => String::from("Unknown"),
+        },
+        stations = status.stations(),
+        attempts = attempts,
+        settings_failed = settings_failed,
+    )
+}
+
+#[derive(Debug, Serialize)]
+struct NetworkBody {
+    ssid: String,
+    // In dBm.
+    rssi: i8,
+    auth: &'static str,
+}
+
+#[derive(Debug, Serialize)]
+struct WifiScanBody {
+    scanning: bool,
+    failed: bool,
+    // How long ago the last scan finished, if there was one.
+    seconds_ago: Option<u64>,
+    // The strongest come first.
+    networks: Vec<NetworkBody>,
+}
+
+impl From<wifi::Scan> for WifiScanBody {
+    fn from(scan: wifi::Scan) -> Self {
+        match scan {
+            wifi::Scan::Idle => Self {
+                scanning: false,
+                failed: false,
+                seconds_ago: None,
+                networks: Vec::new(),
+            },
+            wifi::Scan::Pending => Self {
+                scanning: true,
+                failed: false,
+                seconds_ago: None,
+                networks: Vec::new(),
+            },
+            wifi::Scan::Done { at, networks } => Self {
+                scanning: false,
+                failed: false,
+                seconds_ago: Some(at.elapsed().as_secs()),
+                networks: networks
+                    .iter()
+                    .map(|network| NetworkBody {
+                        ssid: network.ssid.clone(),
+                        rssi: network.rssi,
+                        auth: network.auth(),
+                    })
+                    .collect(),
+            },
+            wifi::Scan::Failed => Self {
+                scanning: false,
+                failed: true,
+                seconds_ago: None,
+                networks: Vec::new(),
+            },
+        }
+    }
+}
+
+// The networks go in a datalist attached to the SSID input, so the user can either pick one or
+// type in a hidden network themselves. While a scan is on the way, this polls until it's done.
+fn wifi_scan(scan: wifi::Scan) -> String {
+    if scan == wifi::Scan::Pending {
+        return String::from(
+            r#"
+            <div
+              id="wifi-scan"
+              hx-get="/api/wifi/scan"
+              hx-trigger="load delay:1s"
+              hx-target="this"
+              hx-swap="outerHTML"
+              hx-confirm="unset"
+            >
+              <p>Scanning...</p>
+            </div>
+            "#,
+        );
+    }
+
+    let (status, networks) = match &scan {
+        wifi::Scan::Done { networks, .. } if networks.is_empty() => {
+            (String::from("<p>No networks found.</p>"), &[][..])
+        }
+        wifi::Scan::Done { networks, .. } => (String::new(), &networks[..]),
+        wifi::Scan::Failed => (
+            String::from(r#"<p role="alert">Couldn't scan for networks. Try again.</p>"#),
+            &[][..],
+        ),
+        wifi::Scan::Idle | wifi::Scan::Pending => (String::new(), &[][..]),
+    };
+
+    // SSIDs are chosen by whoever owns the network, so they have to be escaped.
+    let options = networks
+        .iter()
+        .map(|network| {
+            format!(
+                r#"<option value="{ssid}">{ssid} ({rssi} dBm, {auth})</option>"#,
+                ssid = escape_html(&network.ssid),
+                rssi = network.rssi,
+                auth = network.auth(),
+            )
+        })
+        .collect::<String>();
+
+    format!(
+        r##"
+        <div id="wifi-scan">
+          {status}
+          <button
+            type="button"
+            hx-post="/api/wifi/scan"
+            hx-target="#wifi-scan"
+            hx-swap="outerHTML"
+            hx-confirm="unset"
+          >
+            SCAN
+          </button>
+          <datalist id="ssid-options">{options}</datalist>
+        </div>
+        "##,
+        status = status,
+        options = options,
+    )
+}
+
+fn timer_list(timers: &[io::PendingTimer]) -> String {
+    timers
+        .iter()
+        .map(|timer| {
+            format!(
+                r##"
+                <li>
+                  Squirt in {countdown}
+                  <button
+                    class="cancel-timer-button"
+                    hx-post="/api/schedule/cancel"
+                    hx-vals='{{"id": {id}}}'
+                    hx-target="#timer-list"
+                  >
+                    CANCEL
+                  </button>
+                </li>
+                "##,
+                countdown = format_countdown(timer.remaining.as_secs()),
+                id = timer.id,
+            )
+        })
+        .collect()
+}
+
+#[derive(Debug, Deserialize)]
+struct PatternFormBody {
+    pattern: String,
+}
+
+impl PatternFormBody {
+    // Patterns are looked up by name every time the toy fires, so there's no sense saving a name
+    // that doesn't match any of them.
+    fn exists<P: NvsPartitionId>(&self, nvs_part: EspNvsPartition<P>) -> anyhow::Result<bool> {
+        Ok(config::patterns(nvs_part)?
+            .iter()
+            .any(|pattern| pattern.name == self.pattern))
+    }
+
+    fn save<P: NvsPartitionId>(&self, nvs_part: EspNvsPartition<P>) -> anyhow::Result<()> {
+        config::set_io_pattern(nvs_part, &self.pattern)?;
+
+        log::info!("Selected pattern {:?}.", self.pattern);
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ProgramFormBody {
+    program: String,
+    // In seconds. Zero means the wearer is never asked to check in.
+    check_in: Option<u32>,
+}
+
+impl ProgramFormBody {
+    fn check_in(&self) -> Option<Duration> {
+        form_seconds(self.check_in)
+    }
+}
+
+// Add a user program, replacing any existing user program with the same name.
+fn save_program<P: NvsPartitionId>(
+    nvs_part: EspNvsPartition<P>,
+    program: io::Program,
+) -> anyhow::Result<()> {
+    let mut programs = config::user_programs(nvs_part.clone())?;
+
+    match programs.iter_mut().find(|p| p.name == program.name) {
+        Some(existing) => *existing = program,
+        None if programs.len() >= MAX_USER_PROGRAMS => {
+            anyhow::bail!("You can't upload more than {} programs.", MAX_USER_PROGRAMS);
+        }
+        None => programs.push(program),
+    }
+
+    config::set_user_programs(nvs_part, &programs)?;
+
+    log::info!("Program saved.");
+
+    Ok(())
+}
+
+// Add a user pattern, replacing any existing user pattern with the same name.
+fn save_pattern<P: NvsPartitionId>(
+    nvs_part: EspNvsPartition<P>,
+    pattern: io::Pattern,
+) -> anyhow::Result<()> {
+    let mut patterns = config::user_patterns(nvs_part.clone())?;
+
+    match patterns.iter_mut().find(|p| p.name == pattern.name) {
+        Some(existing) => *existing = pattern,
+        None if patterns.len() >= MAX_USER_PATTERNS => {
+            anyhow::bail!("You can't upload more than {} patterns.", MAX_USER_PATTERNS);
+        }
+        None => patterns.push(pattern),
+    }
+
+    config::set_user_patterns(nvs_part, &patterns)?;
+
+    log::info!("Pattern saved.");
+
+    Ok(())
+}
+
+pub fn serve<P>(
+    nvs_part: EspNvsPartition<P>,
+    signaler: Arc<io::Signaler>,
+    wifi_handle: wifi::WifiHandle,
+) -> anyhow::Result<EspHttpServer<'static>>
+where
+    P: NvsPartitionId + Send + Sync + 'static,
+{
+    let server_config = Configuration {
+        http_port: config::http_port()?,
+        stack_size: HTTP_SERVER_STACK_SIZE,
+        max_uri_handlers: MAX_URI_HANDLERS,
+        ..Default::default()
+    };
+
+    let mut server = EspHttpServer::new(&server_config)?;
+
+    //
+    // Static assets
+    //
+
+    server.fn_handler(
+        "/assets/index.css",
+        Method::Get,
+        |req| -> anyhow::Result<()> {
+            let headers = [("Content-Type", "text/css")];
+
+            let mut resp = req.into_response(200, None, &headers)?;
+            resp.write_all(CSS)?;
+
+            Ok(())
+        },
+    )?;
+
+    server.fn_handler(
+        "/assets/index.js",
+        Method::Get,
+        |req| -> anyhow::Result<()> {
+            let headers = [("Content-Type", "application/javascript")];
+
+            let mut resp = req.into_response(200, None, &headers)?;
+            resp.write_all(JS)?;
+
+            Ok(())
+        },
+    )?;
+
+    server.fn_handler(
+        "/assets/htmx.min.js",
+        Method::Get,
+        |req| -> anyhow::Result<()> {
+            let headers = [
+                ("Content-Type", "text/javascript"),
+                ("Content-Encoding", "gzip"),
+            ];
+
+            let mut resp = req.into_response(200, None, &headers)?;
+            resp.write_all(HTMX)?;
+
+            Ok(())
+        },
+    )?;
+
+    //
+    // HTML pages
+    //
+
+    server.fn_handler("/", Method::Get, |req| -> anyhow::Result<()> {
+        html_resp(req, 200, HTML_INDEX)
+    })?;
+
+    server.fn_handler("/settings", Method::Get, |req| -> anyhow::Result<()> {
+        html_resp(req, 200, HTML_SETTINGS)
+    })?;
+
+    server.fn_handler("/safeword", Method::Get, |req| -> anyhow::Result<()> {
+        html_resp(req, 200, HTML_SAFEWORD)
+    })?;
+
+    //
+    // API endpoints
+    //
+
+    let this_signaler = Arc::clone(&signaler);
+
+    server.fn_handler(
+        "/api/fire",
+        Method::Post,
+        move |mut req| -> anyhow::Result<()> {
+            let req_body = read_body(&mut req)?;
+            let form_body = serde_urlencoded::from_bytes::<FireFormBody>(&req_body)?;
+
+            let outcome = this_signaler.send(io::Signal::Fire(form_body.params()));
+            let feedback = FireFeedback::from(outcome);
+
+            if wants_json(&req) {
+                return json_resp(req, feedback.status, &FireBody::from(&feedback));
+            }
+
+            html_resp(req, feedback.status, fire_feedback(&feedback))
+        },
+    )?;
+
+    let this_signaler = Arc::clone(&signaler);
+
+    server.fn_handler(
+        "/api/stop-all",
+        Method::Post,
+        move |req| -> anyhow::Result<()> {
+            this_signaler.send(io::Signal::EmergencyStop);
+
+            if wants_json(&req) {
+                req.into_status_response(204)?;
+                return Ok(());
+            }
+
+            // Everything on the page that shows what the toy is doing needs to refresh.
+            html_trigger_resp(req, AUTO_CHANGED_EVENT, "")
+        },
+    )?;
+
+    let this_signaler = Arc::clone(&signaler);
+
+    server.fn_handler(
+        "/api/safeword",
+        Method::Post,
+        move |req| -> anyhow::Result<()> {
+            this_signaler.send(io::Signal::Safeword);
+
+            if wants_json(&req) {
+                req.into_status_response(204)?;
+                return Ok(());
+            }
+
+            html_trigger_resp(
+                req,
+                AUTO_CHANGED_EVENT,
+                lockout_notice(this_signaler.snapshot().lockout),
+            )
+        },
+    )?;
+
+    let this_signaler = Arc::clone(&signaler);
+
+    server.fn_handler(
+        "/api/safeword",
+        Method::Get,
+        move |req| -> anyhow::Result<()> {
+            let lockout = this_signaler.snapshot().lockout;
+
+            if wants_json(&req) {
+                return json_resp(
+                    req,
+                    200,
+                    &LockoutBody {
+                        locked: lockout.is_some(),
+                        seconds: lockout.map(|lockout| lockout.as_secs()),
+                    },
+                );
+            }
+
+            html_resp(req, 200, lockout_notice(lockout))
+        },
+    )?;
+
+    let this_signaler = Arc::clone(&signaler);
+
+    server.fn_handler(
+        "/api/check-in",
+        Method::Get,
+        move |req| -> anyhow::Result<()> {
+            let remaining = this_signaler.check_in_remaining();
+
+            if wants_json(&req) {
+                return json_resp(
+                    req,
+                    200,
+                    &CheckInBody {
+                        pending: remaining.is_some(),
+                        seconds: remaining.map(|remaining| remaining.as_secs()),
+                    },
+                );
+            }
+
+            html_resp(req, 200, check_in_prompt(remaining))
+        },
+    )?;
+
+    let this_signaler = Arc::clone(&signaler);
+
+    server.fn_handler(
+        "/api/check-in",
+        Method::Post,
+        move |req| -> anyhow::Result<()> {
+            this_signaler.send(io::Signal::ConfirmCheckIn);
+
+            if wants_json(&req) {
+                req.into_status_response(204)?;
+                return Ok(());
+            }
+
+            html_trigger_resp(req, AUTO_CHANGED_EVENT, check_in_prompt(None))
+        },
+    )?;
+
+    server.fn_handler(
+        "/api/heartbeat/sender",
+        Method::Get,
+        |req| -> anyhow::Result<()> {
+            // Pages only send heartbeats if the toy is going to wait on them.
+            if config::heartbeat_timeout()?.is_zero() {
+                return html_resp(req, 200, "");
+            }
+
+            html_resp(
+                req,
+                200,
+                r#"
+                <div
+                  id="heartbeat"
+                  hx-post="/api/heartbeat"
+                  hx-trigger="load, every 5s"
+                  hx-swap="none"
+                ></div>
+                "#,
+            )
+        },
+    )?;
+
+    let this_signaler = Arc::clone(&signaler);
+
+    server.fn_handler(
+        "/api/heartbeat",
+        Method::Post,
+        move |req| -> anyhow::Result<()> {
+            this_signaler.send(io::Signal::Heartbeat);
+            req.into_status_response(204)?;
+            Ok(())
+        },
+    )?;
+
+    let this_signaler = Arc::clone(&signaler);
+
+    server.fn_handler(
+        "/api/heartbeat",
+        Method::Get,
+        move |req| -> anyhow::Result<()> {
+            let lost = this_signaler.heartbeat_lost();
+
+            if wants_json(&req) {
+                return json_resp(
+                    req,
+                    200,
+                    &HeartbeatLostBody {
+                        lost: lost.is_some(),
+                        seconds: lost.map(|lost| lost.as_secs()),
+                    },
+                );
+            }
+
+            html_resp(req, 200, heartbeat_notice(lost))
+        },
+    )?;
+
+    let this_signaler = Arc::clone(&signaler);
+
+    server.fn_handler(
+        "/api/heartbeat/dismiss",
+        Method::Post,
+        move |req| -> anyhow::Result<()> {
+            this_signaler.dismiss_heartbeat_lost();
+
+            if wants_json(&req) {
+                req.into_status_response(204)?;
+                return Ok(());
+            }
+
+            html_resp(req, 200, heartbeat_notice(None))
+        },
+    )?;
+
+    let this_signaler = Arc::clone(&signaler);
+
+    server.fn_handler(
+        "/api/safeword/lift",
+        Method::Post,
+        move |mut req| -> anyhow::Result<()> {
+            let req_body = read_body(&mut req)?;
+            let form_body = serde_urlencoded::from_bytes::<LiftLockoutFormBody>(&req_body)?;
+
+            let result = this_signaler.lift_lockout(&form_body.pin);
+
+            if wants_json(&req) {
+                return match result {
+                    Ok(()) => {
+                        req.into_status_response(204)?;
+                        Ok(())
+                    }
+                    Err(err) => json_error_resp(req, 403, err),
+                };
+            }
+
+            // These messages never contain user input, so they're safe to include in HTML without
+            // escaping.
+            match result {
+                Ok(()) => html_trigger_resp(
+                    req,
+                    AUTO_CHANGED_EVENT,
+                    r#"<p role="status">The lockout has been lifted. The toy is still disarmed.</p>"#,
+                ),
+                Err(err) => html_resp(req, 403, format!(r#"<p role="alert">{}</p>"#, err)),
+            }
+        },
+    )?;
+
+    let this_signaler = Arc::clone(&signaler);
+
+    server.fn_handler("/api/arm", Method::Get, move |req| -> anyhow::Result<()> {
+        html_resp(
+            req,
+            200,
+            arm_control(
+                this_signaler.is_armed(),
+                config::arming_switch_pin()?.is_some(),
+            ),
+        )
+    })?;
+
+    let this_signaler = Arc::clone(&signaler);
+
+    server.fn_handler("/api/arm", Method::Post, move |req| -> anyhow::Result<()> {
+        if config::arming_switch_pin()?.is_some() {
+            const MESSAGE: &str = "The toy can only be armed with its switch.";
+
+            if wants_json(&req) {
+                return json_error_resp(req, 409, MESSAGE);
+            }
+
+            // The page was loaded before the switch was configured, so swap in the control that
+            // matches the toy now.
+            return html_resp(
+                req,
+                409,
+                format!(
+                    r#"<p role="alert">{}</p>{}"#,
+                    MESSAGE,
+                    arm_control(this_signaler.is_armed(), true)
+                ),
+            );
+        }
+
+        let outcome = this_signaler.send(io::Signal::Arm);
+
+        if wants_json(&req) {
+            if let io::Outcome::LockedOut(_) = outcome {
+                return json_error_resp(req, 403, "The safeword has been used.");
+            }
+
+            req.into_status_response(204)?;
+            return Ok(());
+        }
+
+        html_trigger_resp(
+            req,
+            AUTO_CHANGED_EVENT,
+            arm_control(this_signaler.is_armed(), false),
+        )
+    })?;
+
+    let this_signaler = Arc::clone(&signaler);
+
+    server.fn_handler(
+        "/api/disarm",
+        Method::Post,
+        move |req| -> anyhow::Result<()> {
+            this_signaler.send(io::Signal::Disarm);
+
+            if wants_json(&req) {
+                req.into_status_response(204)?;
+                return Ok(());
+            }
+
+            html_trigger_resp(
+                req,
+                AUTO_CHANGED_EVENT,
+                arm_control(false, config::arming_switch_pin()?.is_some()),
+            )
+        },
+    )?;
+
+    server.fn_handler("/api/hold", Method::Get, |req| -> anyhow::Result<()> {
+        // The button only shows up if the pump controller supports being held.
+        if config::io_hold_message()?.is_none() {
+            return html_resp(req, 200, "");
+        }
+
+        html_resp(
+            req,
+            200,
+            r#"
+            <button
+              id="hold-button"
+              aria-pressed="false"
+              onpointerdown="startHold(this)"
+              onpointerup="releaseHold(this)"
+              onpointerleave="releaseHold(this)"
+              onpointercancel="releaseHold(this)"
+              oncontextmenu="return false"
+            >
+              HOLD
+            </button>
+            "#,
+        )
+    })?;
+
+    let this_signaler = Arc::clone(&signaler);
+
+    server.fn_handler(
+        "/api/hold",
+        Method::Post,
+        move |mut req| -> anyhow::Result<()> {
+            if config::io_hold_message()?.is_none() {
+                return json_error_resp(req, 409, "Hold-to-fire is disabled.");
+            }
+
+            let req_body = read_body(&mut req)?;
+            let form_body = serde_urlencoded::from_bytes::<HoldFormBody>(&req_body)?;
+
+            match this_signaler.hold(form_body.intensity) {
+                Ok(id) => json_resp(req, 200, &HoldBody { id }),
+                Err(err) if err.is::<io::RateLimited>() => json_error_resp(req, 429, err),
+                Err(err) => json_error_resp(req, 409, err),
+            }
+        },
+    )?;
+
+    let this_signaler = Arc::clone(&signaler);
+
+    server.fn_handler(
+        "/api/hold/keepalive",
+        Method::Post,
+        move |mut req| -> anyhow::Result<()> {
+            let req_body = read_body(&mut req)?;
+            let form_body = serde_urlencoded::from_bytes::<HoldUpdateFormBody>(&req_body)?;
+
+            this_signaler.send(io::Signal::KeepAlive(form_body.id));
+
+            req.into_status_response(204)?;
+
+            Ok(())
+        },
+    )?;
+
+    let this_signaler = Arc::clone(&signaler);
+
+    server.fn_handler(
+        "/api/hold/release",
+        Method::Post,
+        move |mut req| -> anyhow::Result<()> {
+            let req_body = read_body(&mut req)?;
+            let form_body = serde_urlencoded::from_bytes::<HoldUpdateFormBody>(&req_body)?;
+
+            this_signaler.send(io::Signal::Release(form_body.id));
+
+            req.into_status_response(204)?;
+
+            Ok(())
+        },
+    )?;
+
+    let this_signaler = Arc::clone(&signaler);
+
+    server.fn_handler(
+        "/api/start",
+        Method::Post,
+        move |mut req| -> anyhow::Result<()> {
+            let req_body = read_body(&mut req)?;
+            let form_body = serde_urlencoded::from_bytes::<AutoFormBody>(&req_body)?;
+
+            let outcome = this_signaler.send(io::Signal::StartAuto {
+                session: form_body.session(),
+                check_in: form_body.check_in(),
+            });
+
+            // Auto mode won't start if the toy is disarmed or locked out. The auto button swaps
+            // itself out, so we say why next to the fire button instead.
+            if let io::Outcome::Disarmed | io::Outcome::LockedOut(_) = outcome {
+                let feedback = FireFeedback::from(outcome);
+
+                if wants_json(&req) {
+                    return json_resp(req, feedback.status, &FireBody::from(&feedback));
+                }
+
+                req.into_response(
+                    feedback.status,
+                    None,
+                    &[
+                        ("Content-Type", "text/html"),
+                        ("HX-Retarget", "#fire-feedback"),
+                    ],
+                )?
+                .write_all(fire_feedback(&feedback).as_bytes())?;
+
+                return Ok(());
+            }
+
+            html_trigger_resp(
+                req,
+                AUTO_CHANGED_EVENT,
+                auto_button(this_signaler.is_auto()),
+            )
+        },
+    )?;
+
+    let this_signaler = Arc::clone(&signaler);
+
+    server.fn_handler(
+        "/api/stop",
+        Method::Post,
+        move |req| -> anyhow::Result<()> {
+            this_signaler.send(io::Signal::StopAuto);
+
+            html_trigger_resp(req, AUTO_CHANGED_EVENT, auto_button(false))
+        },
+    )?;
+
+    let this_signaler = Arc::clone(&signaler);
+
+    server.fn_handler("/api/auto", Method::Get, move |req| -> anyhow::Result<()> {
+        html_resp(req, 200, auto_button(this_signaler.is_auto()))
+    })?;
+
+    let this_signaler = Arc::clone(&signaler);
+
+    server.fn_handler(
+        "/api/pause",
+        Method::Post,
+        move |req| -> anyhow::Result<()> {
+            this_signaler.send(io::Signal::PauseAuto);
+
+            html_trigger_resp(
+                req,
+                AUTO_CHANGED_EVENT,
+                pause_button(this_signaler.is_auto(), this_signaler.is_paused()),
+            )
+        },
+    )?;
+
+    let this_signaler = Arc::clone(&signaler);
+
+    server.fn_handler(
+        "/api/resume",
+        Method::Post,
+        move |req| -> anyhow::Result<()> {
+            this_signaler.send(io::Signal::ResumeAuto);
+
+            html_trigger_resp(
+                req,
+                AUTO_CHANGED_EVENT,
+                pause_button(this_signaler.is_auto(), this_signaler.is_paused()),
+            )
+        },
+    )?;
+
+    let this_signaler = Arc::clone(&signaler);
+
+    server.fn_handler(
+        "/api/paused",
+        Method::Get,
+        move |req| -> anyhow::Result<()> {
+            html_resp(
+                req,
+                200,
+                pause_button(this_signaler.is_auto(), this_signaler.is_paused()),
+            )
+        },
+    )?;
+
+    let this_nvs_part = nvs_part.clone();
+    let this_signaler = Arc::clone(&signaler);
+
+    server.fn_handler(
+        "/api/auto/next",
+        Method::Get,
+        move |req| -> anyhow::Result<()> {
+            let is_auto = this_signaler.is_auto();
+            let is_paused = this_signaler.is_paused();
+            let next_fire = this_signaler.next_auto_fire();
+            let session_remaining = this_signaler.session_remaining();
+
+            if wants_json(&req) {
+                return json_resp(
+                    req,
+                    200,
+                    &NextAutoFireBody {
+                        auto: is_auto,
+                        paused: is_paused,
+                        seconds: next_fire.map(|duration| duration.as_secs()),
+                        session_seconds: session_remaining.map(|duration| duration.as_secs()),
+                    },
+                );
+            }
+
+            let session = match session_remaining {
+                Some(remaining) => format!(
+                    "<p>Auto mode ends in {}</p>",
+                    format_countdown(remaining.as_secs())
+                ),
+                None => String::new(),
+            };
+
+            // Some users would rather not know when the next squirt is coming.
+            if !config::ui_show_countdown(this_nvs_part.clone())? {
+                return html_resp(req, 200, session);
+            }
+
+            let next = match next_fire {
+                _ if is_paused => String::from("<p>Auto mode is paused.</p>"),
+                Some(next_fire) => format!(
+                    r#"
+                    <p>
+                      Next squirt in
+                      <span id="auto-countdown-value" data-seconds="{seconds}">{countdown}</span>
+                    </p>
+                    "#,
+                    seconds = next_fire.as_secs(),
+                    countdown = format_countdown(next_fire.as_secs()),
+                ),
+                None => String::new(),
+            };
+
+            html_resp(req, 200, next + &session)
+        },
+    )?;
+
+    let this_signaler = Arc::clone(&signaler);
+
+    server.fn_handler(
+        "/api/schedule",
+        Method::Get,
+        move |req| -> anyhow::Result<()> {
+            let timers = this_signaler.pending_timers();
+
+            if wants_json(&req) {
+                return json_resp(
+                    req,
+                    200,
+                    &timers.into_iter().map(TimerBody::from).collect::<Vec<_>>(),
+                );
+            }
+
+            html_resp(req, 200, timer_list(&timers))
+        },
+    )?;
+
+    let this_signaler = Arc::clone(&signaler);
+
+    server.fn_handler(
+        "/api/schedule",
+        Method::Post,
+        move |mut req| -> anyhow::Result<()> {
+            let req_body = read_body(&mut req)?;
+            let form_body = match serde_urlencoded::from_bytes::<ScheduleFormBody>(&req_body) {
+                Ok(form_body) => form_body,
+                Err(err) => return schedule_error_resp(req, 400, err),
+            };
+
+            let id = match this_signaler.schedule(form_body.delay(), form_body.params()) {
+                Ok(id) => id,
+                Err(err) => return schedule_error_resp(req, 409, err),
+            };
+
+            if wants_json(&req) {
+                return json_resp(
+                    req,
+                    200,
+                    &TimerBody {
+                        id,
+                        seconds: form_body.delay().as_secs(),
+                    },
+                );
+            }
+
+            // Clear out any error from an earlier attempt.
+            html_resp(
+                req,
+                200,
+                format!(
+                    r#"{}<div id="timer-feedback" hx-swap-oob="true" data-show-errors></div>"#,
+                    timer_list(&this_signaler.pending_timers())
+                ),
+            )
+        },
+    )?;
+
+    let this_signaler = Arc::clone(&signaler);
+
+    server.fn_handler(
+        "/api/schedule/cancel",
+        Method::Post,
+        move |mut req| -> anyhow::Result<()> {
+            let req_body = read_body(&mut req)?;
+            let form_body = serde_urlencoded::from_bytes::<CancelTimerFormBody>(&req_body)?;
+
+            this_signaler.send(io::Signal::CancelTimer(form_body.id));
+
+            if wants_json(&req) {
+                req.into_status_response(204)?;
+                return Ok(());
+            }
+
+            html_resp(req, 200, timer_list(&this_signaler.pending_timers()))
+        },
+    )?;
+
+    let this_nvs_part = nvs_part.clone();
+    let this_wifi_status = Arc::clone(wifi_handle.status());
+
+    server.fn_handler("/api/addr", Method::Get, move |req| -> anyhow::Result<()> {
+        let addr = config::wifi_ip_addr(this_nvs_part.clone())?;
+
+        html_resp(
+            req,
+            200,
+            &match (addr, this_wifi_status.connection()) {
+                (_, wifi::Connection::Connecting { attempt }) => format!(
+                    "
+                    <p>Your Squirtinator is connecting to your home WiFi (attempt {}).</p>
+                    <p>You can still use it in the meantime.</p>
+                    ",
+                    attempt,
+                ),
+                (_, wifi::Connection::BackingOff { attempt, until, .. }) => format!(
+                    "
+                    <p>Your Squirtinator couldn't connect to your home WiFi.</p>
+                    <p>Trying again in {}s (attempt {}).</p>
+                    ",
+                    until.saturating_duration_since(Instant::now()).as_secs(),
+                    attempt,
+                ),
+                // These messages never contain user input, so they're safe to include in HTML
+                // without escaping.
+                (_, wifi::Connection::Failed(failure)) => format!(
+                    "
+                    <p>
+                      Your Squirtinator couldn't connect to your home WiFi, so it's only using
+                      its own hotspot.
+                    </p>
+                    <p>{}</p>
+                    ",
+                    failure,
+                ),
+                (Some(addr), _) => format!(
+                    "
+                    <p>Your Squirtinator is connected to WiFi.</p>
                     <p>
                       http://{}.local<br />
                       http://{}
@@ -291,7 +2144,7 @@ where
                     &config::wifi_hostname()?,
                     addr,
                 ),
-                None => String::from(
+                (None, _) => String::from(
                     "
                     <p>Your Squirtinator is not connected to WiFi.</p>
                     ",
@@ -302,24 +2155,131 @@ where
         Ok(())
     })?;
 
+    let this_nvs_part = nvs_part.clone();
+    let this_wifi_handle = wifi_handle.clone();
+
+    server.fn_handler(
+        "/api/settings/wifi",
+        Method::Put,
+        move |req| -> anyhow::Result<()> {
+            save_wifi_settings(req, this_nvs_part.clone(), &this_wifi_handle)
+        },
+    )?;
+
+    let this_nvs_part = nvs_part.clone();
+    let this_wifi_handle = wifi_handle.clone();
+
+    // This is the same as `PUT /api/settings/wifi`.
+    server.fn_handler(
+        "/api/settings/wifi/test",
+        Method::Post,
+        move |req| -> anyhow::Result<()> {
+            save_wifi_settings(req, this_nvs_part.clone(), &this_wifi_handle)
+        },
+    )?;
+
+    let this_wifi_status = Arc::clone(wifi_handle.status());
+
+    server.fn_handler(
+        "/api/settings/wifi/test",
+        Method::Get,
+        move |req| -> anyhow::Result<()> {
+            let test = this_wifi_status.credential_test();
+
+            if wants_json(&req) {
+                return json_resp(req, 200, &CredentialTestBody::from(test));
+            }
+
+            html_resp(req, 200, credential_test_result(test))
+        },
+    )?;
+
+    let this_wifi_status = Arc::clone(wifi_handle.status());
+
+    server.fn_handler(
+        "/api/wifi/status",
+        Method::Get,
+        move |req| -> anyhow::Result<()> {
+            if wants_json(&req) {
+                return json_resp(req, 200, &WifiStatusBody::from(&*this_wifi_status));
+            }
+
+            html_resp(req, 200, wifi_status_panel(&this_wifi_status))
+        },
+    )?;
+
+    let this_wifi_status = Arc::clone(wifi_handle.status());
+
+    server.fn_handler(
+        "/api/wifi/scan",
+        Method::Get,
+        move |req| -> anyhow::Result<()> {
+            let scan = this_wifi_status.scan();
+
+            if wants_json(&req) {
+                return json_resp(req, 200, &WifiScanBody::from(scan));
+            }
+
+            html_resp(req, 200, wifi_scan(scan))
+        },
+    )?;
+
+    let this_wifi_handle = wifi_handle.clone();
+
+    // Scanning takes a few seconds, and it has to wait if the toy is in the middle of connecting
+    // to the local network, so we don't hold up the HTTP server waiting for it. Clients should
+    // poll `GET /api/wifi/scan` for the results.
+    server.fn_handler(
+        "/api/wifi/scan",
+        Method::Post,
+        move |req| -> anyhow::Result<()> {
+            this_wifi_handle.scan()?;
+
+            if wants_json(&req) {
+                return json_resp(
+                    req,
+                    202,
+                    &WifiScanBody::from(this_wifi_handle.status().scan()),
+                );
+            }
+
+            html_resp(req, 200, wifi_scan(this_wifi_handle.status().scan()))
+        },
+    )?;
+
     let this_nvs_part = nvs_part.clone();
 
     server.fn_handler(
-        "/api/settings/wifi",
-        Method::Put,
-        move |mut req| -> anyhow::Result<()> {
-            let req_body = read_body(&mut req)?;
-            let form_body = serde_urlencoded::from_bytes::<WifiSettingsFormBody>(&req_body)?;
+        "/api/settings/wifi/failure",
+        Method::Get,
+        move |req| -> anyhow::Result<()> {
+            let failure = config::wifi_failure(this_nvs_part.clone())?;
+            let failure = failure.as_deref().and_then(wifi::Failure::from_code);
 
-            form_body.save(this_nvs_part.clone())?;
+            if wants_json(&req) {
+                return json_resp(
+                    req,
+                    200,
+                    &WifiFailureBody {
+                        failure: failure.map(|failure| failure.code()),
+                        message: failure.map(|failure| failure.to_string()),
+                    },
+                );
+            }
 
+            // These messages never contain user input, so they're safe to include in HTML without
+            // escaping.
             html_resp(
                 req,
                 200,
-                "<p>WiFi settings saved. Restart the device to connect to the new network.</p>",
-            )?;
-
-            Ok(())
+                match failure {
+                    Some(failure) => format!(
+                        r#"<p role="alert">The toy couldn't connect to this network last time. {}</p>"#,
+                        failure
+                    ),
+                    None => String::new(),
+                },
+            )
         },
     )?;
 
@@ -342,10 +2302,11 @@ where
                           id="ssid-input"
                           name="ssid"
                           type="text"
+                          list="ssid-options"
                           value="{}"
                         />
                         "##,
-                        ssid
+                        escape_html(&ssid)
                     ),
                 )?;
             } else {
@@ -357,6 +2318,7 @@ where
     )?;
 
     let this_nvs_part = nvs_part.clone();
+    let this_signaler = Arc::clone(&signaler);
 
     server.fn_handler(
         "/api/settings/freq",
@@ -367,6 +2329,10 @@ where
 
             form_body.save(this_nvs_part.clone())?;
 
+            // Apply the new settings to the current countdown rather than waiting for the next
+            // one.
+            this_signaler.send(io::Signal::AutoSettingsChanged);
+
             req.into_status_response(204)?;
 
             Ok(())
@@ -409,5 +2375,493 @@ where
         )
     })?;
 
+    server.fn_handler("/api/intensity", Method::Get, |req| -> anyhow::Result<()> {
+        html_resp(
+            req,
+            200,
+            format!(
+                r#"
+                <span id="intensity-slider" class="slider">
+                  <input
+                    id="intensity-input"
+                    type="range"
+                    name="intensity"
+                    value="{default}"
+                    min="0"
+                    max="255"
+                    oninput="updateSliderValue(this, 'intensity-value')"
+                  />
+                  <span id="intensity-value" class="slider-value">{default}</span>
+                </span>
+                "#,
+                default = config::io_default_intensity()?,
+            ),
+        )
+    })?;
+
+    let this_nvs_part = nvs_part.clone();
+
+    server.fn_handler(
+        "/api/settings/distribution",
+        Method::Get,
+        move |req| -> anyhow::Result<()> {
+            let selected = config::freq_distribution_kind(this_nvs_part.clone())?;
+
+            let options = io::DistributionKind::ALL
+                .iter()
+                .map(|kind| {
+                    format!(
+                        r#"<option value="{name}"{selected}>{label}</option>"#,
+                        name = kind.name(),
+                        label = kind.label(),
+                        selected = if *kind == selected { " selected" } else { "" },
+                    )
+                })
+                .collect::<String>();
+
+            // Only show the parameters that apply to the selected distribution.
+            let hidden_unless = |kinds: &[io::DistributionKind]| {
+                if kinds.contains(&selected) {
+                    ""
+                } else {
+                    "hidden"
+                }
+            };
+
+            html_resp(
+                req,
+                200,
+                format!(
+                    r#"
+                    <label for="distribution-select">Timing</label>
+                    <select
+                      id="distribution-select"
+                      name="distribution"
+                      onchange="showDistributionParams(this)"
+                    >
+                      {options}
+                    </select>
+                    <label data-distributions="exponential normal" {mean_hidden}>
+                      Average wait (seconds)
+                      <input type="number" name="mean" value="{mean}" min="0" />
+                    </label>
+                    <label data-distributions="normal" {std_dev_hidden}>
+                      Spread (seconds)
+                      <input type="number" name="std_dev" value="{std_dev}" min="0" />
+                    </label>
+                    <label data-distributions="short long" {skew_hidden}>
+                      Weighting
+                      <input type="number" name="skew" value="{skew}" min="1" max="{max_skew}" />
+                    </label>
+                    "#,
+                    options = options,
+                    mean = config::freq_mean(this_nvs_part.clone())?,
+                    std_dev = config::freq_std_dev(this_nvs_part.clone())?,
+                    skew = config::freq_skew(this_nvs_part.clone())?,
+                    max_skew = FreqSettingsFormBody::MAX_SKEW,
+                    mean_hidden = hidden_unless(&[
+                        io::DistributionKind::Exponential,
+                        io::DistributionKind::Normal,
+                    ]),
+                    std_dev_hidden = hidden_unless(&[io::DistributionKind::Normal]),
+                    skew_hidden =
+                        hidden_unless(&[io::DistributionKind::Short, io::DistributionKind::Long,]),
+                ),
+            )
+        },
+    )?;
+
+    let this_nvs_part = nvs_part.clone();
+
+    server.fn_handler(
+        "/api/settings/ui",
+        Method::Get,
+        move |req| -> anyhow::Result<()> {
+            html_resp(
+                req,
+                200,
+                format!(
+                    r#"
+                    <label>
+                      <input
+                        id="show-countdown-input"
+                        type="checkbox"
+                        name="show_countdown"
+                        {checked}
+                      />
+                      Show a countdown to the next squirt in auto mode
+                    </label>
+                    "#,
+                    checked = if config::ui_show_countdown(this_nvs_part.clone())? {
+                        "checked"
+                    } else {
+                        ""
+                    },
+                ),
+            )
+        },
+    )?;
+
+    let this_nvs_part = nvs_part.clone();
+
+    server.fn_handler(
+        "/api/settings/ui",
+        Method::Put,
+        move |mut req| -> anyhow::Result<()> {
+            let req_body = read_body(&mut req)?;
+            let form_body = serde_urlencoded::from_bytes::<UiSettingsFormBody>(&req_body)?;
+
+            form_body.save(this_nvs_part.clone())?;
+
+            req.into_status_response(204)?;
+
+            Ok(())
+        },
+    )?;
+
+    let this_nvs_part = nvs_part.clone();
+
+    server.fn_handler(
+        "/api/settings/intensity",
+        Method::Get,
+        move |req| -> anyhow::Result<()> {
+            let range = config::intensity_range(this_nvs_part.clone())?;
+            let (min, max) = range.unwrap_or((0, config::io_default_intensity()?));
+
+            html_resp(
+                req,
+                200,
+                format!(
+                    r#"
+                    <label>
+                      <input
+                        id="random-intensity-input"
+                        type="checkbox"
+                        name="random_intensity"
+                        {checked}
+                      />
+                      Randomize intensity in auto mode
+                    </label>
+                    <label for="min-intensity-input">Minimum intensity</label>
+                    <span class="slider">
+                      <input
+                        id="min-intensity-input"
+                        type="range"
+                        name="min_intensity"
+                        value="{min}"
+                        min="0"
+                        max="255"
+                        oninput="updateSliderValue(this, 'min-intensity-value')"
+                      />
+                      <span id="min-intensity-value" class="slider-value">{min}</span>
+                    </span>
+                    <label for="max-intensity-input">Maximum intensity</label>
+                    <span class="slider">
+                      <input
+                        id="max-intensity-input"
+                        type="range"
+                        name="max_intensity"
+                        value="{max}"
+                        min="0"
+                        max="255"
+                        oninput="updateSliderValue(this, 'max-intensity-value')"
+                      />
+                      <span id="max-intensity-value" class="slider-value">{max}</span>
+                    </span>
+                    "#,
+                    checked = if range.is_some() { "checked" } else { "" },
+                    min = min,
+                    max = max,
+                ),
+            )
+        },
+    )?;
+
+    let this_nvs_part = nvs_part.clone();
+
+    server.fn_handler(
+        "/api/settings/intensity",
+        Method::Put,
+        move |mut req| -> anyhow::Result<()> {
+            let req_body = read_body(&mut req)?;
+            let form_body = serde_urlencoded::from_bytes::<IntensitySettingsFormBody>(&req_body)?;
+
+            form_body.save(this_nvs_part.clone())?;
+
+            req.into_status_response(204)?;
+
+            Ok(())
+        },
+    )?;
+
+    let this_nvs_part = nvs_part.clone();
+
+    server.fn_handler(
+        "/api/patterns",
+        Method::Get,
+        move |req| -> anyhow::Result<()> {
+            json_resp(req, 200, &config::patterns(this_nvs_part.clone())?)
+        },
+    )?;
+
+    let this_nvs_part = nvs_part.clone();
+
+    server.fn_handler(
+        "/api/patterns",
+        Method::Post,
+        move |mut req| -> anyhow::Result<()> {
+            let req_body = read_body(&mut req)?;
+
+            let pattern = match serde_json::from_slice::<io::Pattern>(&req_body) {
+                Ok(pattern) => pattern,
+                Err(err) => return json_error_resp(req, 400, err),
+            };
+
+            if let Err(err) = pattern.validate() {
+                return json_error_resp(req, 400, err);
+            }
+
+            if let Err(err) = save_pattern(this_nvs_part.clone(), pattern) {
+                return json_error_resp(req, 400, err);
+            }
+
+            req.into_status_response(204)?;
+
+            Ok(())
+        },
+    )?;
+
+    let this_nvs_part = nvs_part.clone();
+
+    server.fn_handler(
+        "/api/patterns/select",
+        Method::Get,
+        move |req| -> anyhow::Result<()> {
+            let selected = config::io_pattern(this_nvs_part.clone())?;
+
+            // Pattern names are validated to only contain characters that are safe to include
+            // in HTML without escaping.
+            let options = config::patterns(this_nvs_part.clone())?
+                .iter()
+                .map(|pattern| {
+                    format!(
+                        r#"<option value="{name}"{selected}>{name}</option>"#,
+                        name = pattern.name,
+                        selected = if pattern.name == selected {
+                            " selected"
+                        } else {
+                            ""
+                        },
+                    )
+                })
+                .collect::<String>();
+
+            html_resp(
+                req,
+                200,
+                format!(
+                    r#"
+                    <select
+                      id="pattern-select"
+                      name="pattern"
+                      hx-put="/api/patterns/selected"
+                      hx-trigger="change"
+                      hx-swap="none"
+                    >
+                      {options}
+                    </select>
+                    "#,
+                    options = options,
+                ),
+            )
+        },
+    )?;
+
+    let this_nvs_part = nvs_part.clone();
+
+    server.fn_handler(
+        "/api/patterns/selected",
+        Method::Put,
+        move |mut req| -> anyhow::Result<()> {
+            let req_body = read_body(&mut req)?;
+            let form_body = serde_urlencoded::from_bytes::<PatternFormBody>(&req_body)?;
+
+            if !form_body.exists(this_nvs_part.clone())? {
+                return json_error_resp(
+                    req,
+                    400,
+                    format!("There's no pattern called {:?}.", form_body.pattern),
+                );
+            }
+
+            form_body.save(this_nvs_part.clone())?;
+
+            req.into_status_response(204)?;
+
+            Ok(())
+        },
+    )?;
+
+    let this_nvs_part = nvs_part.clone();
+
+    server.fn_handler(
+        "/api/programs",
+        Method::Get,
+        move |req| -> anyhow::Result<()> {
+            json_resp(req, 200, &config::programs(this_nvs_part.clone())?)
+        },
+    )?;
+
+    let this_nvs_part = nvs_part.clone();
+
+    server.fn_handler(
+        "/api/programs",
+        Method::Post,
+        move |mut req| -> anyhow::Result<()> {
+            let req_body = read_body(&mut req)?;
+
+            let program = match serde_json::from_slice::<io::Program>(&req_body) {
+                Ok(program) => program,
+                Err(err) => return json_error_resp(req, 400, err),
+            };
+
+            if let Err(err) = program.validate() {
+                return json_error_resp(req, 400, err);
+            }
+
+            if let Err(err) = save_program(this_nvs_part.clone(), program) {
+                return json_error_resp(req, 400, err);
+            }
+
+            req.into_status_response(204)?;
+
+            Ok(())
+        },
+    )?;
+
+    let this_nvs_part = nvs_part.clone();
+    let this_signaler = Arc::clone(&signaler);
+
+    server.fn_handler(
+        "/api/program",
+        Method::Get,
+        move |req| -> anyhow::Result<()> {
+            let is_paused = this_signaler.is_paused();
+            let status = this_signaler.program_status();
+
+            if wants_json(&req) {
+                return json_resp(
+                    req,
+                    200,
+                    &status.map(|status| ProgramStatusBody {
+                        name: status.name,
+                        phase: status.phase + 1,
+                        phases: status.phases,
+                        phase_seconds: status.phase_remaining.as_secs(),
+                        seconds: status.remaining.as_secs(),
+                        paused: is_paused,
+                    }),
+                );
+            }
+
+            match status {
+                Some(status) => html_resp(req, 200, program_status(&status, is_paused)),
+                // If the status was polling, the program just finished on its own, and the rest
+                // of the auto mode controls need to catch up.
+                None if req.header("HX-Trigger") == Some("program-status") => html_trigger_resp(
+                    req,
+                    AUTO_CHANGED_EVENT,
+                    program_form(&config::programs(this_nvs_part.clone())?),
+                ),
+                None => html_resp(
+                    req,
+                    200,
+                    program_form(&config::programs(this_nvs_part.clone())?),
+                ),
+            }
+        },
+    )?;
+
+    let this_nvs_part = nvs_part.clone();
+    let this_signaler = Arc::clone(&signaler);
+
+    server.fn_handler(
+        "/api/program/start",
+        Method::Post,
+        move |mut req| -> anyhow::Result<()> {
+            let req_body = read_body(&mut req)?;
+            let form_body = serde_urlencoded::from_bytes::<ProgramFormBody>(&req_body)?;
+
+            let Some(program) = config::programs(this_nvs_part.clone())?
+                .into_iter()
+                .find(|program| program.name == form_body.program)
+            else {
+                return json_error_resp(
+                    req,
+                    404,
+                    format!("Program {:?} does not exist.", form_body.program),
+                );
+            };
+
+            this_signaler.send(io::Signal::StartProgram {
+                program,
+                check_in: form_body.check_in(),
+            });
+
+            let body = match this_signaler.program_status() {
+                Some(status) => program_status(&status, this_signaler.is_paused()),
+                None => program_form(&config::programs(this_nvs_part.clone())?),
+            };
+
+            html_trigger_resp(req, AUTO_CHANGED_EVENT, body)
+        },
+    )?;
+
+    let this_nvs_part = nvs_part.clone();
+    let this_signaler = Arc::clone(&signaler);
+
+    server.fn_handler(
+        "/api/program/stop",
+        Method::Post,
+        move |req| -> anyhow::Result<()> {
+            this_signaler.send(io::Signal::StopAuto);
+
+            html_trigger_resp(
+                req,
+                AUTO_CHANGED_EVENT,
+                program_form(&config::programs(this_nvs_part.clone())?),
+            )
+        },
+    )?;
+
+    let this_signaler = Arc::clone(&signaler);
+
+    server.fn_handler(
+        "/api/state",
+        Method::Get,
+        move |req| -> anyhow::Result<()> {
+            json_resp(req, 200, &StateBody::from(this_signaler.snapshot()))
+        },
+    )?;
+
+    let this_signaler = Arc::clone(&signaler);
+    let this_wifi_status = Arc::clone(wifi_handle.status());
+
+    server.fn_handler(
+        "/api/diagnostics",
+        Method::Get,
+        move |req| -> anyhow::Result<()> {
+            json_resp(
+                req,
+                200,
+                &DiagnosticsBody {
+                    outcomes: this_signaler.outcome_counts().into(),
+                    stations: this_wifi_status.stations(),
+                    wifi: this_wifi_status.connection().into(),
+                },
+            )
+        },
+    )?;
+
     Ok(server)
 }
@@ -1,12 +1,16 @@
+mod i2c_driver;
+mod program;
+
 use std::{
     sync::{
-        atomic::{AtomicBool, Ordering},
-        Arc,
+        atomic::{AtomicU32, Ordering},
+        mpsc, Arc, Mutex, MutexGuard, PoisonError,
     },
     thread,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
+use anyhow::{anyhow, bail};
 use esp_idf_svc::{
     hal::gpio,
     hal::i2c,
@@ -15,61 +19,1012 @@ use esp_idf_svc::{
 use rand::prelude::*;
 use rand::rngs::SmallRng;
 
-use crate::{config, queue::RendezvousQueue, Never};
+use squirtinator::io::{distribution, driver, governor, message, pattern, scheduler, state};
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+use crate::{config, Never};
+
+use driver::{NullPumpDriver, PumpDriver};
+use i2c_driver::I2cPumpDriver;
+use program::ProgramRun;
+use scheduler::{Clock, Scheduler, SystemClock};
+use state::StateMachine;
+
+pub use distribution::{DistributionKind, IntervalDistribution};
+pub use governor::{GovernorLimits, RateLimited};
+pub use message::MessageTemplate;
+pub use pattern::{builtin_patterns, Pattern};
+pub use program::{builtin_programs, Program};
+pub use state::{Outcome, SafewordConfig, Snapshot, State};
+
+// The parameters the toy is fired with. Anything that's unset falls back to the default from the
+// config file.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FireParams {
+    pub intensity: Option<u8>,
+    pub duration: Option<Duration>,
+    // Overrides the pattern the user has selected.
+    pub pattern: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Signal {
-    Fire,
-    StartAuto,
+    Fire(FireParams),
+    // Start auto mode. If there's a session length, auto mode stops by itself after that long. If
+    // there's a check-in interval, the wearer is asked that often whether they want to keep going.
+    StartAuto {
+        session: Option<Duration>,
+        check_in: Option<Duration>,
+    },
+    // Start auto mode, but have it follow a program rather than the user's frequency settings.
+    StartProgram {
+        program: Program,
+        check_in: Option<Duration>,
+    },
+    // The wearer wants to keep going.
+    ConfirmCheckIn,
+    // The remote is still there.
+    Heartbeat,
     StopAuto,
+    PauseAuto,
+    ResumeAuto,
+    // The user changed the auto mode settings, so the current countdown should be recalculated.
+    AutoSettingsChanged,
+    // Fire the toy once, after a delay. Use `Signaler::schedule` to get an ID for the timer.
+    Schedule {
+        id: u32,
+        delay: Duration,
+        params: FireParams,
+    },
+    CancelTimer(u32),
+    // Keep the pump running until the user lets go. Use `Signaler::hold` to get an ID for the hold.
+    Hold {
+        id: u32,
+        intensity: Option<u8>,
+    },
+    // The user is still holding down the button for the hold with this ID.
+    KeepAlive(u32),
+    // The user let go of the button for the hold with this ID.
+    Release(u32),
+    // Stop everything: auto mode, timers, and whatever the pump is doing right now. Nothing can
+    // fire again until the toy is armed.
+    EmergencyStop,
+    Arm,
+    // Like an emergency stop, but for when the toy is disarmed as a matter of course, e.g.
+    // because it's been idle or the WiFi dropped.
+    Disarm,
+    // Like an emergency stop, but it also locks everyone out for a while, so that nobody
+    // controlling the toy remotely can start it up again.
+    Safeword,
+}
+
+// What the pump thread should do next.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum PumpRequest {
+    Fire(FireParams),
+    // Keep the pump running until the user lets go.
+    Hold { id: u32, intensity: Option<u8> },
+    // Tell the pump controller to stop, even if we don't think it's doing anything.
+    Stop,
+}
+
+// How many of each outcome there have been since the device booted.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct OutcomeCounts {
+    pub accepted: u32,
+    pub busy: u32,
+    pub rate_limited: u32,
+    pub disarmed: u32,
+    pub locked_out: u32,
+    pub test_mode: u32,
+    // Requests that were accepted, but where something went wrong talking to the pump controller.
+    pub pump_errors: u32,
+}
+
+#[derive(Debug, Default)]
+struct OutcomeCounters {
+    accepted: AtomicU32,
+    busy: AtomicU32,
+    rate_limited: AtomicU32,
+    disarmed: AtomicU32,
+    locked_out: AtomicU32,
+    test_mode: AtomicU32,
+    pump_errors: AtomicU32,
+}
+
+impl OutcomeCounters {
+    fn count(&self, outcome: Outcome) {
+        let counter = match outcome {
+            Outcome::Accepted => &self.accepted,
+            Outcome::Busy => &self.busy,
+            Outcome::RateLimited(_) => &self.rate_limited,
+            Outcome::Disarmed => &self.disarmed,
+            Outcome::LockedOut(_) => &self.locked_out,
+            Outcome::TestMode => &self.test_mode,
+        };
+
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn count_pump_error(&self) {
+        self.pump_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> OutcomeCounts {
+        OutcomeCounts {
+            accepted: self.accepted.load(Ordering::Relaxed),
+            busy: self.busy.load(Ordering::Relaxed),
+            rate_limited: self.rate_limited.load(Ordering::Relaxed),
+            disarmed: self.disarmed.load(Ordering::Relaxed),
+            locked_out: self.locked_out.load(Ordering::Relaxed),
+            test_mode: self.test_mode.load(Ordering::Relaxed),
+            pump_errors: self.pump_errors.load(Ordering::Relaxed),
+        }
+    }
+}
+
+// Messages for the pump thread while it's in the middle of something.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PumpControl {
+    KeepAlive(u32),
+    Release(u32),
+    Stop,
+}
+
+// A one-shot timer that hasn't fired yet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PendingTimer {
+    pub id: u32,
+    pub remaining: Duration,
+}
+
+// A snapshot of the program that's currently running, for display in the UI.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProgramStatus {
+    pub name: String,
+    // The index of the current phase, starting at zero.
+    pub phase: usize,
+    pub phases: usize,
+    pub phase_remaining: Duration,
+    pub remaining: Duration,
+}
+
+impl ProgramStatus {
+    fn new(program: &Program, phase: &program::PhaseState) -> Self {
+        Self {
+            name: program.name.clone(),
+            phase: phase.index,
+            phases: program.phases.len(),
+            phase_remaining: phase.phase_remaining,
+            remaining: phase.remaining,
+        }
+    }
 }
 
 #[derive(Debug)]
 pub struct Signaler {
-    fire_queue: RendezvousQueue<()>,
-    auto_queue: RendezvousQueue<bool>,
-    is_auto: AtomicBool,
+    // The state machine only lets one request through at a time, so this channel only needs room
+    // for that and an emergency stop.
+    pump_sender: mpsc::SyncSender<PumpRequest>,
+    pump_receiver: Mutex<mpsc::Receiver<PumpRequest>>,
+    control_sender: mpsc::SyncSender<PumpControl>,
+    control_receiver: Mutex<mpsc::Receiver<PumpControl>>,
+    next_hold_id: AtomicU32,
+    machine: Mutex<StateMachine>,
+    outcomes: OutcomeCounters,
+    auto_commands: mpsc::Sender<Signal>,
+    auto_receiver: Mutex<mpsc::Receiver<Signal>>,
+    next_auto_fire: Mutex<Option<Instant>>,
+    // The status of the current program, along with the time it was taken.
+    program_status: Mutex<Option<(ProgramStatus, Instant)>>,
+    session_end: Mutex<Option<Instant>>,
+    // When auto mode pauses if the wearer doesn't check in, if they've been asked to.
+    check_in_deadline: Mutex<Option<Instant>>,
+    // When we last gave up on the remote, so we can tell the user once it comes back.
+    heartbeat_lost: Mutex<Option<Instant>>,
+    next_timer_id: AtomicU32,
+    // The IDs and deadlines of the one-shot timers that haven't fired yet.
+    timers: Mutex<Vec<(u32, Instant)>>,
 }
 
 impl Signaler {
-    pub fn new() -> Self {
+    pub fn new(
+        limits: GovernorLimits,
+        idle_timeout: Duration,
+        heartbeat_timeout: Duration,
+        safeword: SafewordConfig,
+        is_test_mode: bool,
+    ) -> Self {
+        let (pump_sender, pump_receiver) = mpsc::sync_channel(2);
+        let (control_sender, control_receiver) = mpsc::sync_channel(MAX_PUMP_CONTROLS);
+        let (auto_commands, auto_receiver) = mpsc::channel();
+
         Self {
-            fire_queue: RendezvousQueue::new(),
-            auto_queue: RendezvousQueue::new(),
-            is_auto: AtomicBool::new(false),
+            pump_sender,
+            pump_receiver: Mutex::new(pump_receiver),
+            control_sender,
+            control_receiver: Mutex::new(control_receiver),
+            next_hold_id: AtomicU32::new(0),
+            machine: Mutex::new(StateMachine::new(
+                limits,
+                idle_timeout,
+                heartbeat_timeout,
+                safeword,
+                is_test_mode,
+            )),
+            outcomes: OutcomeCounters::default(),
+            auto_commands,
+            auto_receiver: Mutex::new(auto_receiver),
+            next_auto_fire: Mutex::new(None),
+            program_status: Mutex::new(None),
+            session_end: Mutex::new(None),
+            check_in_deadline: Mutex::new(None),
+            heartbeat_lost: Mutex::new(None),
+            next_timer_id: AtomicU32::new(0),
+            timers: Mutex::new(Vec::new()),
+        }
+    }
+
+    fn lock_machine(&self) -> MutexGuard<'_, StateMachine> {
+        self.machine.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    // Every request to run the pump goes through here, no matter where it came from, so the state
+    // machine gets a say in all of them.
+    fn request(&self, request: PumpRequest) -> Outcome {
+        let outcome = self.try_request(request);
+        self.outcomes.count(outcome);
+        outcome
+    }
+
+    fn try_request(&self, request: PumpRequest) -> Outcome {
+        let mut machine = self.lock_machine();
+        let outcome = machine.request(Instant::now());
+
+        match outcome {
+            Outcome::Accepted | Outcome::TestMode => {}
+            Outcome::Busy => {
+                log::info!("Toy is already active. Skipping this I2C write.");
+                return outcome;
+            }
+            Outcome::RateLimited(limited) => {
+                log::warn!("{}", limited);
+                return outcome;
+            }
+            Outcome::Disarmed => {
+                log::info!("Toy is disarmed. Skipping this I2C write.");
+                return outcome;
+            }
+            Outcome::LockedOut(_) => {
+                log::info!("Toy is locked out. Skipping this I2C write.");
+                return outcome;
+            }
+        }
+
+        if self.pump_sender.try_send(request).is_err() {
+            log::error!("The pump is not listening.");
+            machine.finish(Instant::now(), Duration::ZERO);
+            return Outcome::Busy;
+        }
+
+        outcome
+    }
+
+    pub fn outcome_counts(&self) -> OutcomeCounts {
+        self.outcomes.snapshot()
+    }
+
+    fn control_pump(&self, control: PumpControl) {
+        // If the pump thread is falling behind, there's no point in queueing up more keep-alives.
+        // The pump thread is always draining this channel while it's doing something, so an
+        // emergency stop won't get dropped when it matters.
+        if let Err(mpsc::TrySendError::Disconnected(_)) = self.control_sender.try_send(control) {
+            log::error!("The pump is not listening.");
+        }
+    }
+
+    // Start running the pump until the user lets go, returning the ID of the hold. The caller
+    // needs to keep sending `Signal::KeepAlive` with this ID, or the pump stops.
+    pub fn hold(&self, intensity: Option<u8>) -> anyhow::Result<u32> {
+        let id = self.next_hold_id.fetch_add(1, Ordering::Relaxed);
+
+        match self.send(Signal::Hold { id, intensity }) {
+            Outcome::Accepted | Outcome::TestMode => {}
+            Outcome::Busy => bail!("The toy is already active."),
+            Outcome::Disarmed => bail!("The toy is disarmed."),
+            Outcome::LockedOut(_) => bail!("The safeword has been used."),
+            Outcome::RateLimited(limited) => return Err(limited.into()),
+        }
+
+        log::info!("Starting hold {}.", id);
+
+        Ok(id)
+    }
+
+    // Stop auto mode, timers, and the pump. The state machine needs to be disarmed first, so that
+    // nothing new can sneak in while we're stopping.
+    fn stop_everything(&self) {
+        self.set_program_status(None);
+        self.set_session_end(None);
+        self.set_check_in_deadline(None);
+        self.lock_timers().clear();
+        self.stop_pump();
+    }
+
+    pub fn stop_pump(&self) {
+        // This interrupts whatever the pump is doing right now, and then the stop request makes
+        // sure the pump controller hears about it, even if we don't think the pump was running.
+        self.control_pump(PumpControl::Stop);
+
+        if let Err(mpsc::TrySendError::Disconnected(_)) =
+            self.pump_sender.try_send(PumpRequest::Stop)
+        {
+            log::error!("The pump is not listening.");
         }
     }
 
-    pub fn send(&self, signal: Signal) {
-        match signal {
-            Signal::Fire => {
-                // We don't block if the queue is full. This has the effect that if the user
-                // presses the button to trigger the toy while it's already doing something, it
-                // will be a no-op rather than queue up I2C writes. We want to wait until the toy
-                // is done doing its thing before we allow it to be activated again.
-                if !self.fire_queue.try_send(()) {
-                    log::info!("Toy is already active. Skipping this I2C write.");
+    // Requests to run the pump report what happened to them. Everything else is always accepted.
+    pub fn send(&self, signal: Signal) -> Outcome {
+        // Anything the user does counts as activity, so the toy doesn't disarm itself while
+        // they're using it. Every open page sends heartbeats whether or not anyone is using it, so
+        // those don't count.
+        if !matches!(signal, Signal::Heartbeat) {
+            self.lock_machine().touch(Instant::now());
+        }
+
+        // Starting something from the remote, or keeping it going, means the remote is still there.
+        let is_heartbeat = matches!(
+            signal,
+            Signal::Heartbeat
+                | Signal::Hold { .. }
+                | Signal::KeepAlive(_)
+                | Signal::StartAuto { .. }
+                | Signal::StartProgram { .. }
+        );
+
+        if is_heartbeat {
+            self.lock_machine().heartbeat(Instant::now());
+        }
+
+        // These only go through if the toy is armed.
+        let needs_arming = matches!(
+            signal,
+            Signal::StartAuto { .. } | Signal::StartProgram { .. } | Signal::Schedule { .. }
+        );
+
+        if needs_arming {
+            let outcome = self.lock_machine().check_armed(Instant::now());
+
+            if outcome != Outcome::Accepted {
+                log::info!("Toy is not armed. Ignoring {:?}.", signal);
+                return outcome;
+            }
+        }
+
+        match &signal {
+            Signal::Fire(params) => return self.request(PumpRequest::Fire(params.clone())),
+            Signal::Hold { id, intensity } => {
+                return self.request(PumpRequest::Hold {
+                    id: *id,
+                    intensity: *intensity,
+                })
+            }
+            Signal::KeepAlive(id) => {
+                self.control_pump(PumpControl::KeepAlive(*id));
+                return Outcome::Accepted;
+            }
+            Signal::Release(id) => {
+                log::info!("Releasing hold {}.", id);
+                self.control_pump(PumpControl::Release(*id));
+                return Outcome::Accepted;
+            }
+            Signal::Heartbeat => return Outcome::Accepted,
+            Signal::Arm => {
+                let outcome = self.lock_machine().arm(Instant::now());
+
+                if outcome != Outcome::Accepted {
+                    log::info!("Toy is locked out. Not arming it.");
+                    return outcome;
                 }
+
+                log::info!("Arming the toy.");
             }
-            // Staring or stopping auto mode should immediately override the previous setting
-            // without blocking.
-            Signal::StartAuto => {
-                self.auto_queue.try_recv();
-                self.auto_queue.send(true);
-                self.is_auto.store(true, Ordering::Relaxed);
-                log::info!("Starting auto mode.");
+            Signal::StartAuto { session, .. } => {
+                self.lock_machine().start_auto(Instant::now());
+                self.set_program_status(None);
+
+                match session {
+                    Some(session) => {
+                        log::info!("Starting auto mode for {}s.", session.as_secs());
+                    }
+                    None => log::info!("Starting auto mode."),
+                }
+            }
+            Signal::StartProgram { program, .. } => {
+                self.lock_machine().start_auto(Instant::now());
+
+                // The auto mode thread will keep this up to date, but we set it here so the UI
+                // can show the program as running straight away.
+                self.set_program_status(
+                    program
+                        .at(Duration::ZERO)
+                        .map(|phase| ProgramStatus::new(program, &phase)),
+                );
+
+                log::info!("Starting program {:?}.", program.name);
             }
             Signal::StopAuto => {
-                self.auto_queue.try_recv();
-                self.auto_queue.send(false);
-                self.is_auto.store(false, Ordering::Relaxed);
+                self.lock_machine().stop_auto();
+                self.set_program_status(None);
+                self.set_session_end(None);
+                self.set_check_in_deadline(None);
+                self.lock_timers().clear();
                 log::info!("Stopping auto mode.");
             }
+            Signal::EmergencyStop => {
+                log::warn!("Emergency stop!");
+                self.lock_machine().disarm();
+                self.stop_everything();
+            }
+            Signal::Disarm => {
+                log::info!("Disarming the toy.");
+                self.lock_machine().disarm();
+                self.stop_everything();
+            }
+            Signal::Safeword => {
+                log::warn!("Safeword! Locking everything.");
+                self.lock_machine().safeword(Instant::now());
+                self.stop_everything();
+            }
+            Signal::PauseAuto => {
+                self.lock_machine().pause_auto();
+                log::info!("Pausing auto mode.");
+            }
+            Signal::ResumeAuto => {
+                self.lock_machine().resume_auto();
+                log::info!("Resuming auto mode.");
+            }
+            Signal::AutoSettingsChanged => {}
+            Signal::ConfirmCheckIn => {
+                self.set_check_in_deadline(None);
+                log::info!("Wearer checked in.");
+            }
+            // `schedule` has already added this to the list of timers.
+            Signal::Schedule { delay, .. } => {
+                log::info!("Scheduling a squirt in {}s.", delay.as_secs());
+            }
+            Signal::CancelTimer(id) => {
+                self.lock_timers().retain(|(timer_id, _)| timer_id != id);
+                log::info!("Cancelling a scheduled squirt.");
+            }
+        }
+
+        // The channel is unbounded, so this never blocks, and the auto mode thread wakes up as
+        // soon as the command arrives. This only fails if the auto mode thread has died.
+        if self.auto_commands.send(signal).is_err() {
+            log::error!("Auto mode is not running.");
         }
+
+        Outcome::Accepted
+    }
+
+    // What the toy is doing right now.
+    pub fn snapshot(&self) -> Snapshot {
+        self.lock_machine().snapshot(Instant::now())
+    }
+
+    pub fn is_armed(&self) -> bool {
+        self.snapshot().is_armed
+    }
+
+    // End a safeword lockout early. This needs the PIN, so that whoever is controlling the toy
+    // remotely can't do it.
+    pub fn lift_lockout(&self, pin: &str) -> anyhow::Result<()> {
+        self.lock_machine().lift_lockout(Instant::now(), pin)?;
+        log::info!("Safeword lockout lifted.");
+        Ok(())
     }
 
     pub fn is_auto(&self) -> bool {
-        self.is_auto.load(Ordering::Relaxed)
+        self.snapshot().is_auto
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.snapshot().is_paused
+    }
+
+    // How long until auto mode fires next, or `None` if auto mode isn't counting down.
+    pub fn next_auto_fire(&self) -> Option<Duration> {
+        self.next_auto_fire
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .map(|deadline| deadline.saturating_duration_since(Instant::now()))
+    }
+
+    fn set_next_auto_fire(&self, deadline: Option<Instant>) {
+        *self
+            .next_auto_fire
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = deadline;
+    }
+
+    // The status of the current program, or `None` if there's no program running.
+    pub fn program_status(&self) -> Option<ProgramStatus> {
+        let status = self
+            .program_status
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+
+        status.as_ref().map(|(status, taken)| {
+            // The program clock doesn't move while auto mode is paused.
+            let elapsed = if self.is_paused() {
+                Duration::ZERO
+            } else {
+                taken.elapsed()
+            };
+
+            ProgramStatus {
+                phase_remaining: status.phase_remaining.saturating_sub(elapsed),
+                remaining: status.remaining.saturating_sub(elapsed),
+                ..status.clone()
+            }
+        })
+    }
+
+    fn set_program_status(&self, status: Option<ProgramStatus>) {
+        *self
+            .program_status
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) =
+            status.map(|status| (status, Instant::now()));
+    }
+
+    // How long until the current auto mode session ends, or `None` if it doesn't have an end (or
+    // auto mode is paused).
+    pub fn session_remaining(&self) -> Option<Duration> {
+        self.session_end
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .map(|deadline| deadline.saturating_duration_since(Instant::now()))
+    }
+
+    fn set_session_end(&self, deadline: Option<Instant>) {
+        *self
+            .session_end
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = deadline;
+    }
+
+    // How long the wearer has left to check in before auto mode pauses, or `None` if they haven't
+    // been asked to.
+    pub fn check_in_remaining(&self) -> Option<Duration> {
+        self.check_in_deadline
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .map(|deadline| deadline.saturating_duration_since(Instant::now()))
+    }
+
+    // How long ago we gave up on the remote and stopped everything, if we have since the user last
+    // dismissed it.
+    pub fn heartbeat_lost(&self) -> Option<Duration> {
+        self.heartbeat_lost
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .map(|lost| lost.elapsed())
+    }
+
+    pub fn dismiss_heartbeat_lost(&self) {
+        *self
+            .heartbeat_lost
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = None;
+    }
+
+    // The remote has gone quiet, so stop auto mode and anything the pump is doing.
+    fn lose_heartbeat(&self) {
+        self.lock_machine().lose_heartbeat();
+
+        self.set_program_status(None);
+        self.set_session_end(None);
+        self.set_check_in_deadline(None);
+        *self
+            .heartbeat_lost
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = Some(Instant::now());
+
+        self.stop_pump();
+    }
+
+    fn set_check_in_deadline(&self, deadline: Option<Instant>) {
+        *self
+            .check_in_deadline
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = deadline;
+    }
+
+    // Fire the toy once after `delay`, returning the ID of the timer so it can be cancelled.
+    pub fn schedule(&self, delay: Duration, params: FireParams) -> anyhow::Result<u32> {
+        // We take the slot while we still hold the lock, so that two requests at once can't both
+        // get the last one. Like the program status, the auto mode thread keeps the list of timers
+        // up to date, but adding it here means the UI reflects the change straight away.
+        let id = {
+            let mut timers = self.lock_timers();
+
+            if timers.len() >= MAX_TIMERS {
+                bail!(
+                    "You can't schedule more than {} squirts at once.",
+                    MAX_TIMERS
+                );
+            }
+
+            let id = self.next_timer_id.fetch_add(1, Ordering::Relaxed);
+            timers.push((id, Instant::now() + delay));
+            id
+        };
+
+        let err = match self.send(Signal::Schedule { id, delay, params }) {
+            Outcome::Disarmed => "The toy is disarmed.",
+            Outcome::LockedOut(_) => "The safeword has been used.",
+            _ => return Ok(id),
+        };
+
+        self.lock_timers().retain(|(timer_id, _)| *timer_id != id);
+
+        bail!(err)
+    }
+
+    // The one-shot timers that haven't fired yet, soonest first.
+    pub fn pending_timers(&self) -> Vec<PendingTimer> {
+        let now = Instant::now();
+
+        let mut timers = self
+            .lock_timers()
+            .iter()
+            .map(|(id, deadline)| PendingTimer {
+                id: *id,
+                remaining: deadline.saturating_duration_since(now),
+            })
+            .collect::<Vec<_>>();
+
+        timers.sort_by_key(|timer| timer.remaining);
+
+        timers
+    }
+
+    fn lock_timers(&self) -> MutexGuard<'_, Vec<(u32, Instant)>> {
+        self.timers.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+// Each pending timer holds on to a copy of the parameters it was scheduled with, so we put a cap on
+// them.
+const MAX_TIMERS: usize = 16;
+
+// Keep-alives only matter while the pump thread is holding, so we don't need to buffer many.
+const MAX_PUMP_CONTROLS: usize = 8;
+
+// The time to wait before the next squirt in auto mode. If a program is running, its current
+// phase decides the range to pick from.
+fn auto_interval<P: NvsPartitionId>(
+    nvs_part: EspNvsPartition<P>,
+    rng: &mut impl Rng,
+    phase: Option<&program::PhaseState>,
+) -> anyhow::Result<Duration> {
+    // We read these each time because they're configurable by the user and may change at any
+    // time. Programs still use the distribution the user picked; if its parameters fall outside
+    // the phase's range, the samples just get clamped to it.
+    let distribution = config::freq_distribution(nvs_part.clone())?;
+
+    let (min, max) = match phase {
+        Some(phase) => (phase.min_freq, phase.max_freq),
+        None => (
+            Duration::from_secs(config::freq_min(nvs_part.clone())?.into()),
+            Duration::from_secs(config::freq_max(nvs_part.clone())?.into()),
+        ),
+    };
+
+    Ok(distribution.sample(rng, min, max))
+}
+
+// If the user has configured a range, each squirt in auto mode gets a random intensity. A
+// program's phase can override this.
+fn auto_intensity<P: NvsPartitionId>(
+    nvs_part: EspNvsPartition<P>,
+    rng: &mut impl Rng,
+    phase: Option<&program::PhaseState>,
+) -> anyhow::Result<Option<u8>> {
+    if let Some(intensity) = phase.and_then(|phase| phase.intensity) {
+        return Ok(Some(intensity));
+    }
+
+    Ok(config::intensity_range(nvs_part)?
+        .map(|(min_intensity, max_intensity)| rng.gen_range(min_intensity..=max_intensity)))
+}
+
+// Auto mode is over, either because the session or the program has run its course.
+fn finish_auto(signaler: &Signaler) {
+    let mut machine = signaler.lock_machine();
+    machine.stop_auto();
+    machine.touch(Instant::now());
+}
+
+// Start counting down to the next check-in, if there are check-ins, and forget about any check-in
+// that's waiting for an answer.
+fn start_check_ins<C: Clock>(
+    check_in: &mut Scheduler<C>,
+    check_in_grace: &mut Scheduler<C>,
+    interval: Option<Duration>,
+) {
+    check_in_grace.stop();
+
+    match interval {
+        Some(interval) => check_in.start(interval),
+        None => check_in.stop(),
+    }
+}
+
+fn run_auto<P>(nvs_part: EspNvsPartition<P>, signaler: &Signaler) -> anyhow::Result<()>
+where
+    P: NvsPartitionId,
+{
+    let mut rng = SmallRng::from_entropy();
+    let clock = SystemClock;
+    let mut scheduler = Scheduler::new(clock);
+    // The session timer pauses along with auto mode, so it gets its own scheduler.
+    let mut session = Scheduler::new(clock);
+    let mut program: Option<ProgramRun> = None;
+    let mut current_phase: Option<usize> = None;
+    // How often to ask the wearer whether they want to keep going, if at all. The time between
+    // check-ins only counts while auto mode is running, so it gets its own scheduler, and so does
+    // the time the wearer has to answer.
+    let mut check_in_interval: Option<Duration> = None;
+    let mut check_in = Scheduler::new(clock);
+    let mut check_in_grace = Scheduler::new(clock);
+    let grace = config::check_in_grace()?;
+    // One-shot timers don't pause along with auto mode; they fire when the user asked them to.
+    let mut timers: Vec<(u32, Instant, FireParams)> = Vec::new();
+
+    let commands = signaler
+        .auto_receiver
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner);
+
+    loop {
+        // We need to wake up when the program moves on to its next phase, even if we're not due
+        // to fire yet, so that the new phase's settings take effect.
+        let phase_timeout = program
+            .as_ref()
+            .filter(|_| !signaler.is_paused())
+            .and_then(|run| run.state(clock.now()))
+            .map(|phase| phase.phase_remaining);
+
+        let timer_timeout = timers
+            .iter()
+            .map(|(_, deadline, _)| deadline.saturating_duration_since(clock.now()))
+            .min();
+
+        // A scheduled squirt means the toy is expecting to fire, so it isn't idle.
+        let idle_timeout = if timers.is_empty() {
+            signaler.lock_machine().idle_remaining(clock.now())
+        } else {
+            None
+        };
+
+        let heartbeat_timeout = signaler.lock_machine().heartbeat_remaining(clock.now());
+
+        let timeout = [
+            scheduler.timeout(),
+            session.timeout(),
+            check_in.timeout(),
+            check_in_grace.timeout(),
+            phase_timeout,
+            timer_timeout,
+            idle_timeout,
+            heartbeat_timeout,
+        ]
+        .into_iter()
+        .flatten()
+        .min();
+
+        // Rather than sleeping until the next squirt, we wait for the next command with a
+        // timeout. That way, stopping auto mode or changing its settings takes effect
+        // immediately.
+        let command = match timeout {
+            Some(timeout) => match commands.recv_timeout(timeout) {
+                Ok(command) => Some(command),
+                Err(mpsc::RecvTimeoutError::Timeout) => None,
+                Err(mpsc::RecvTimeoutError::Disconnected) => bail!("Signaler was dropped."),
+            },
+            // Block until the user enables auto mode so we don't get caught in a busy loop.
+            None => Some(commands.recv()?),
+        };
+
+        match command {
+            Some(Signal::StartAuto {
+                session: session_length,
+                check_in: new_check_in_interval,
+            }) => {
+                check_in_interval = new_check_in_interval;
+                start_check_ins(&mut check_in, &mut check_in_grace, check_in_interval);
+
+                program = None;
+                scheduler.start(auto_interval(nvs_part.clone(), &mut rng, None)?);
+
+                match session_length {
+                    Some(session_length) => session.start(session_length),
+                    None => session.stop(),
+                }
+            }
+            Some(Signal::StartProgram {
+                program: new_program,
+                check_in: new_check_in_interval,
+            }) => {
+                check_in_interval = new_check_in_interval;
+                start_check_ins(&mut check_in, &mut check_in_grace, check_in_interval);
+
+                let run = ProgramRun::new(new_program, clock.now());
+                let phase = run.state(clock.now());
+                scheduler.start(auto_interval(nvs_part.clone(), &mut rng, phase.as_ref())?);
+                // Programs have their own length.
+                session.stop();
+                program = Some(run);
+            }
+            Some(Signal::StopAuto | Signal::EmergencyStop | Signal::Disarm | Signal::Safeword) => {
+                program = None;
+                scheduler.stop();
+                session.stop();
+                timers.clear();
+            }
+            Some(Signal::PauseAuto) => {
+                if let Some(run) = &mut program {
+                    run.pause(clock.now());
+                }
+
+                scheduler.pause();
+                session.pause();
+                check_in.pause();
+                check_in_grace.pause();
+            }
+            Some(Signal::ResumeAuto) => {
+                if let Some(run) = &mut program {
+                    run.resume(clock.now());
+                }
+
+                scheduler.resume();
+                session.resume();
+                check_in.resume();
+                check_in_grace.resume();
+            }
+            Some(Signal::AutoSettingsChanged) if scheduler.is_running() => {
+                let phase = program.as_ref().and_then(|run| run.state(clock.now()));
+                scheduler.reschedule(auto_interval(nvs_part.clone(), &mut rng, phase.as_ref())?);
+            }
+            Some(Signal::Schedule { id, delay, params }) => {
+                timers.push((id, clock.now() + delay, params));
+            }
+            Some(Signal::CancelTimer(id)) => {
+                timers.retain(|(timer_id, _, _)| *timer_id != id);
+            }
+            Some(Signal::ConfirmCheckIn) if check_in_grace.is_running() => {
+                start_check_ins(&mut check_in, &mut check_in_grace, check_in_interval);
+            }
+            Some(_) | None => {}
+        }
+
+        let phase = program.as_ref().and_then(|run| run.state(clock.now()));
+
+        match (&program, &phase) {
+            (Some(run), None) => {
+                log::info!("Program {:?} has finished.", run.program().name);
+
+                program = None;
+                scheduler.stop();
+                finish_auto(signaler);
+            }
+            (Some(run), Some(phase)) if current_phase.is_some_and(|index| index != phase.index) => {
+                log::info!(
+                    "Program {:?} is moving on to phase {}.",
+                    run.program().name,
+                    phase.index + 1
+                );
+
+                // With stepped transitions, the new phase's range might be very different from the
+                // old one, so we don't want to stay stuck on a wait picked from the old range.
+                scheduler.reschedule(auto_interval(nvs_part.clone(), &mut rng, Some(phase))?);
+            }
+            _ => {}
+        }
+
+        current_phase = phase.as_ref().map(|phase| phase.index);
+
+        if timers.is_empty()
+            && signaler.lock_machine().idle_remaining(clock.now()) == Some(Duration::ZERO)
+        {
+            log::info!("Toy has been idle for too long.");
+            signaler.send(Signal::Disarm);
+        }
+
+        if signaler.lock_machine().heartbeat_remaining(clock.now()) == Some(Duration::ZERO) {
+            log::warn!("Lost contact with the remote. Stopping auto mode.");
+
+            program = None;
+            scheduler.stop();
+            session.stop();
+            signaler.lose_heartbeat();
+        }
+
+        if session.is_due() {
+            log::info!("Auto mode session has ended.");
+
+            session.stop();
+            scheduler.stop();
+            finish_auto(signaler);
+        }
+
+        // Check-ins only happen while auto mode is on.
+        if !scheduler.is_running() {
+            check_in.stop();
+            check_in_grace.stop();
+        }
+
+        if check_in.is_due() {
+            log::info!("Asking the wearer to check in.");
+
+            check_in.stop();
+            check_in_grace.start(grace);
+        }
+
+        let missed_check_in = check_in_grace.is_due();
+
+        if missed_check_in {
+            log::warn!("Wearer didn't check in. Pausing auto mode.");
+
+            // If the wearer resumes auto mode, they get asked again after the usual interval.
+            start_check_ins(&mut check_in, &mut check_in_grace, check_in_interval);
+
+            signaler.send(Signal::PauseAuto);
+            signaler.stop_pump();
+        }
+
+        // The pause is picked up right away the next time around the loop, and we don't want auto
+        // mode to squirt in the meantime.
+        if scheduler.is_due() && !missed_check_in {
+            // If this doesn't go through, we just wait for the next one.
+            signaler.request(PumpRequest::Fire(FireParams {
+                intensity: auto_intensity(nvs_part.clone(), &mut rng, phase.as_ref())?,
+                duration: None,
+                pattern: phase.as_ref().and_then(|phase| phase.pattern.clone()),
+            }));
+
+            scheduler.start(auto_interval(nvs_part.clone(), &mut rng, phase.as_ref())?);
+        }
+
+        // Timer expiry goes through the same path as any other fire, so the toy still gets its
+        // block time between squirts.
+        let now = clock.now();
+        let (due, pending) = timers
+            .into_iter()
+            .partition::<Vec<_>, _>(|(_, deadline, _)| *deadline <= now);
+
+        timers = pending;
+
+        for (_, _, params) in due {
+            log::info!("Scheduled squirt is due.");
+            signaler.request(PumpRequest::Fire(params));
+        }
+
+        signaler.set_next_auto_fire(scheduler.deadline());
+        signaler.set_session_end(session.deadline());
+        signaler.set_check_in_deadline(check_in_grace.deadline());
+        signaler.set_program_status(
+            program
+                .as_ref()
+                .zip(phase.as_ref())
+                .map(|(run, phase)| ProgramStatus::new(run.program(), phase)),
+        );
+        *signaler.lock_timers() = timers
+            .iter()
+            .map(|(id, deadline, _)| (*id, *deadline))
+            .collect();
     }
 }
 
@@ -82,82 +1037,379 @@ pub fn listen<P>(
 where
     P: NvsPartitionId + Send + Sync + 'static,
 {
-    let mut rng = SmallRng::from_entropy();
+    let this_nvs_part = nvs_part.clone();
     let this_signaler = Arc::clone(&signaler);
 
-    thread::spawn(move || {
-        let mut fire = || -> anyhow::Result<()> {
-            // We read these each time because they're configurable by the user and may change at
-            // any time.
-            let min_seconds = config::freq_min(nvs_part.clone())?;
-            let max_seconds = config::freq_max(nvs_part.clone())?;
+    thread::spawn(move || loop {
+        if let Err(err) = run_auto(this_nvs_part.clone(), &this_signaler) {
+            log::error!("{:?}", err);
+            this_signaler.set_next_auto_fire(None);
+            this_signaler.set_program_status(None);
+            this_signaler.set_session_end(None);
+            this_signaler.set_check_in_deadline(None);
+            this_signaler.lock_timers().clear();
+        }
+    });
+
+    let mut pins = config::io_pins(pins)?;
+
+    if let Some(pin) = pins.arm_switch_pin()? {
+        let this_signaler = Arc::clone(&signaler);
+
+        thread::spawn(move || {
+            let Err(err) = watch_arm_switch(pin, &this_signaler);
+            log::error!("{:?}", err);
+
+            // If we can't tell what the switch is doing, the safe thing to do is disarm.
+            this_signaler.send(Signal::Disarm);
+        });
+    }
+
+    if let Some(pin) = pins.safeword_button_pin()? {
+        let this_signaler = Arc::clone(&signaler);
+
+        thread::spawn(move || {
+            let Err(err) = watch_safeword_button(pin, &this_signaler);
+            log::error!("{:?}", err);
+        });
+    }
+
+    let mut driver = pump_driver(i2c, &mut pins)?;
+    let message = config::io_message()?;
+    let default_intensity = config::io_default_intensity()?;
+    let block_time = config::io_block_time()?;
+    let hold_message = config::io_hold_message()?;
+    let hold_stop_message = config::io_hold_stop_message()?;
+    let hold_timeout = config::io_hold_timeout()?;
+    let hold_max_time = config::io_hold_max_time()?;
+
+    if let Err(err) = driver.health_check() {
+        log::warn!("Pump controller health check failed: {:?}", err);
+    }
+
+    // In case the pump was left running when the device was reset. If nothing answers, that's no
+    // reason to stop serving requests; the toy stays disarmed, and each write after this reports
+    // its own errors.
+    if let Err(err) = driver.stop() {
+        log::error!("Could not stop the pump: {:?}", err);
+        signaler.send(Signal::Disarm);
+    }
 
-            let seconds_to_wait = rng.gen_range(min_seconds..max_seconds);
-            thread::sleep(Duration::from_secs(seconds_to_wait.into()));
+    let requests = signaler
+        .pump_receiver
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner);
+
+    let controls = signaler
+        .control_receiver
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner);
+
+    loop {
+        // Wait until we get a message to trigger the pump.
+        let request = requests.recv()?;
 
-            // Check in case auto mode was disabled while we were sleeping.
-            if this_signaler.auto_queue.try_peek() != Some(false) {
-                this_signaler.fire_queue.try_send(());
+        // Anything left in here is from before this request, like keep-alives from an earlier hold
+        // or an emergency stop that came in while nothing was running.
+        while controls.try_recv().is_ok() {}
+
+        let on_time = match request {
+            PumpRequest::Stop => {
+                if let Err(err) = driver.stop() {
+                    log::error!("Could not stop the pump: {:?}", err);
+                    signaler.outcomes.count_pump_error();
+                }
+
+                continue;
             }
+            // The toy may have been stopped after this request was accepted but before we got to
+            // it.
+            _ if !signaler.is_armed() => {
+                log::info!("Toy was disarmed. Skipping this I2C write.");
+                signaler
+                    .lock_machine()
+                    .finish(Instant::now(), Duration::ZERO);
+                continue;
+            }
+            PumpRequest::Fire(params) => {
+                // We read this each time because the user can select a different pattern at any
+                // time.
+                let pattern = match &params.pattern {
+                    Some(name) => config::pattern(nvs_part.clone(), name),
+                    None => config::selected_pattern(nvs_part.clone()),
+                };
 
-            Ok(())
-        };
+                // A bad value in NVS shouldn't take down the pump thread.
+                let pattern = match pattern {
+                    Ok(pattern) => pattern,
+                    Err(err) => {
+                        log::error!(
+                            "Could not load the pattern. Skipping this squirt: {:?}",
+                            err
+                        );
+                        signaler
+                            .lock_machine()
+                            .finish(Instant::now(), Duration::ZERO);
+                        continue;
+                    }
+                };
 
-        let mut is_auto = false;
+                let timeline = pattern.timeline(
+                    &message,
+                    params.intensity.unwrap_or(default_intensity),
+                    params.duration.unwrap_or_default(),
+                );
 
-        let mut wait_then_fire = || -> anyhow::Result<()> {
-            if is_auto {
-                // Poll for whether the user has disabled auto mode.
-                if this_signaler.auto_queue.try_recv() == Some(false) {
-                    is_auto = false;
-                } else {
-                    fire()?;
+                log::info!("Playing pattern {:?}.", pattern.name);
+
+                if let Err(err) = play(driver.as_mut(), &controls, &timeline) {
+                    recover(driver.as_mut(), &signaler, err);
                 }
-            // Block until the user enables auto mode so we don't get caught in a busy loop.
-            } else if this_signaler.auto_queue.recv() {
-                is_auto = true;
-                fire()?;
+
+                // If the pattern was cut short, this overestimates, which is the safe direction to
+                // be wrong in.
+                timeline.charged_time(block_time)
             }
+            PumpRequest::Hold { id, intensity } => {
+                match &hold_message {
+                    Some(hold_message) => {
+                        // Don't let a hold run past the governor's limit on how long the pump can
+                        // run.
+                        let max_time =
+                            match signaler.lock_machine().remaining_on_time(Instant::now()) {
+                                Some(remaining) => hold_max_time.min(remaining),
+                                None => hold_max_time,
+                            };
+
+                        let start = Instant::now();
+
+                        hold(
+                            driver.as_mut(),
+                            &controls,
+                            id,
+                            &hold_message
+                                .render(intensity.unwrap_or(default_intensity), Duration::ZERO),
+                            &hold_stop_message,
+                            hold_timeout,
+                            max_time,
+                        )
+                        .unwrap_or_else(|err| {
+                            recover(driver.as_mut(), &signaler, err);
+                            start.elapsed()
+                        })
+                    }
+                    None => {
+                        log::warn!("Hold-to-fire is disabled in the config.");
+                        Duration::ZERO
+                    }
+                }
+            }
+        };
+
+        thread::sleep(block_time);
+
+        // The toy isn't ready for another request until the block time is up.
+        signaler.lock_machine().finish(Instant::now(), on_time);
+    }
+}
+
+// Something went wrong talking to the pump controller partway through a request. We can't tell
+// what state that left the pump in, so we make a best effort to stop it, and then carry on with
+// the next request rather than take the whole pump thread down.
+fn recover(driver: &mut dyn PumpDriver, signaler: &Signaler, err: anyhow::Error) {
+    log::error!("Pump controller error: {:?}", err);
+    signaler.outcomes.count_pump_error();
+
+    if let Err(err) = driver.stop() {
+        log::error!("Could not stop the pump: {:?}", err);
+    }
+}
 
-            Ok(())
+// Run the pump until the user lets go, the client stops sending keep-alives, or we hit the
+// maximum run time, whichever comes first. Returns how long the pump ran for.
+fn hold(
+    driver: &mut dyn PumpDriver,
+    controls: &mpsc::Receiver<PumpControl>,
+    id: u32,
+    message: &[u8],
+    stop_message: &[u8],
+    timeout: Duration,
+    max_time: Duration,
+) -> anyhow::Result<Duration> {
+    let start = Instant::now();
+
+    driver.fire(message)?;
+
+    let result = loop {
+        let Some(remaining) = max_time.checked_sub(start.elapsed()) else {
+            log::warn!("Hold {} reached the maximum run time.", id);
+            break Ok(());
         };
 
-        loop {
-            if let Err(err) = wait_then_fire() {
-                log::error!("{:?}", err);
+        match controls.recv_timeout(timeout.min(remaining)) {
+            Ok(PumpControl::KeepAlive(update_id)) if update_id == id => {}
+            Ok(PumpControl::Release(update_id)) if update_id == id => break Ok(()),
+            Ok(PumpControl::Stop) => {
+                log::warn!("Hold {} was stopped.", id);
+                break Ok(());
+            }
+            // These are left over from an earlier hold.
+            Ok(_) => {}
+            Err(mpsc::RecvTimeoutError::Timeout) if start.elapsed() >= max_time => {
+                log::warn!("Hold {} reached the maximum run time.", id);
+                break Ok(());
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                log::warn!("Lost contact with the client during hold {}.", id);
+                break Ok(());
+            }
+            Err(mpsc::RecvTimeoutError::Disconnected) => {
+                break Err(anyhow!("Signaler was dropped."))
             }
         }
-    });
+    };
 
-    let mut pins = config::io_pins(pins)?;
-    let address = config::io_address()?;
-    let message = config::io_message()?;
-    let baudrate = config::io_baudrate()?;
-    let timeout = config::io_timeout()?;
-    let block_time = config::io_block_time()?;
-    let test_mode = config::io_test_mode()?;
+    // No matter why the hold ended, the pump controller needs to be told to stop.
+    driver.stop_with(stop_message)?;
 
-    let i2c_config = i2c::I2cConfig {
-        baudrate: baudrate.into(),
-        ..Default::default()
-    };
+    result.map(|()| start.elapsed())
+}
 
-    let mut driver = i2c::I2cDriver::new(i2c, pins.sda_pin()?, pins.scl_pin()?, &i2c_config)?;
+// Wait for `duration`, unless there's an emergency stop in the meantime. Returns whether there was.
+fn wait(controls: &mpsc::Receiver<PumpControl>, duration: Duration) -> anyhow::Result<bool> {
+    let deadline = Instant::now() + duration;
 
     loop {
-        // Wait until we get a message to trigger the pump over I2C.
-        signaler.fire_queue.recv();
+        let Some(remaining) = deadline.checked_duration_since(Instant::now()) else {
+            return Ok(false);
+        };
 
-        log::info!(
-            "Activating the pump over I2C at address {:#04x} with message {:?}.",
-            address,
-            message,
-        );
+        match controls.recv_timeout(remaining) {
+            Ok(PumpControl::Stop) => return Ok(true),
+            // These only matter during a hold.
+            Ok(PumpControl::KeepAlive(_) | PumpControl::Release(_)) => {}
+            Err(mpsc::RecvTimeoutError::Timeout) => return Ok(false),
+            Err(mpsc::RecvTimeoutError::Disconnected) => bail!("Signaler was dropped."),
+        }
+    }
+}
+
+// How often to check the arming switch and the safeword button. Waiting for them to read the same
+// twice in a row also debounces them.
+const SWITCH_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// The switch arms the toy while it's closed, which pulls the pin low, and disarms it when it's
+// opened. It has to be seen open before it can arm the toy, so that a switch that was left on
+// doesn't arm the toy the moment it boots.
+fn watch_arm_switch(pin: gpio::AnyIOPin, signaler: &Signaler) -> anyhow::Result<Never> {
+    let mut switch = gpio::PinDriver::input(pin)?;
+    switch.set_pull(gpio::Pull::Up)?;
+
+    let mut was_closed = switch.is_low();
+
+    if was_closed {
+        log::warn!("The arming switch is already on. Turn it off and on again to arm the toy.");
+    }
 
-        if !test_mode {
-            driver.write(address, &message, timeout)?;
+    loop {
+        thread::sleep(SWITCH_POLL_INTERVAL);
+
+        let is_closed = switch.is_low();
+
+        if is_closed == was_closed {
+            continue;
         }
 
-        thread::sleep(block_time);
+        thread::sleep(SWITCH_POLL_INTERVAL);
+
+        if switch.is_low() != is_closed {
+            continue;
+        }
+
+        was_closed = is_closed;
+
+        signaler.send(if is_closed {
+            Signal::Arm
+        } else {
+            Signal::Disarm
+        });
+    }
+}
+
+// The safeword button is pressed when it pulls the pin low.
+fn watch_safeword_button(pin: gpio::AnyIOPin, signaler: &Signaler) -> anyhow::Result<Never> {
+    let mut button = gpio::PinDriver::input(pin)?;
+    button.set_pull(gpio::Pull::Up)?;
+
+    let mut was_pressed = false;
+
+    loop {
+        thread::sleep(SWITCH_POLL_INTERVAL);
+
+        let is_pressed = button.is_low();
+
+        if is_pressed && !was_pressed {
+            thread::sleep(SWITCH_POLL_INTERVAL);
+
+            if button.is_low() {
+                signaler.send(Signal::Safeword);
+            }
+        }
+
+        was_pressed = is_pressed;
+    }
+}
+
+fn play(
+    driver: &mut dyn PumpDriver,
+    controls: &mpsc::Receiver<PumpControl>,
+    timeline: &pattern::Timeline,
+) -> anyhow::Result<()> {
+    let start = Instant::now();
+
+    for event in &timeline.events {
+        // Sleep until the event is due rather than for a fixed amount of time, so that time spent
+        // talking to the pump controller doesn't cause the pattern to drift.
+        if let Some(delay) = event.at.checked_sub(start.elapsed()) {
+            if wait(controls, delay)? {
+                log::warn!("Pattern was stopped.");
+                return driver.stop();
+            }
+        }
+
+        match &event.action {
+            pattern::Action::Fire(message) => driver.fire(message)?,
+            pattern::Action::Stop => driver.stop()?,
+        }
+    }
+
+    if let Some(delay) = timeline.duration.checked_sub(start.elapsed()) {
+        if wait(controls, delay)? {
+            log::warn!("Pattern was stopped.");
+            return driver.stop();
+        }
+    }
+
+    Ok(())
+}
+
+fn pump_driver(i2c: i2c::I2C0, pins: &mut config::IoPins) -> anyhow::Result<Box<dyn PumpDriver>> {
+    match config::io_driver()? {
+        config::IoDriver::I2c => {
+            let i2c_config = i2c::I2cConfig {
+                baudrate: config::io_baudrate()?.into(),
+                ..Default::default()
+            };
+
+            let driver = i2c::I2cDriver::new(i2c, pins.sda_pin()?, pins.scl_pin()?, &i2c_config)?;
+
+            Ok(Box::new(I2cPumpDriver::new(
+                driver,
+                config::io_address()?,
+                config::io_timeout()?,
+                config::io_stop_message()?,
+            )))
+        }
+        config::IoDriver::Null => Ok(Box::new(NullPumpDriver::new())),
     }
 }
@@ -0,0 +1,276 @@
+use std::time::Duration;
+
+use rand::Rng;
+use serde::Deserialize;
+
+// How auto mode picks the time to wait before the next squirt, within the range the user has
+// chosen.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IntervalDistribution {
+    // Every wait in the range is equally likely.
+    Uniform,
+    // Memoryless: no matter how long it's been, the next squirt is always just as likely to happen
+    // in the next second. The `mean` is in seconds.
+    Exponential { mean: f64 },
+    // Waits cluster around the `mean`. Both parameters are in seconds.
+    Normal { mean: f64, std_dev: f64 },
+    // Waits are weighted toward the short end of the range. Higher `skew` means more weight.
+    Short { skew: f64 },
+    // Waits are weighted toward the long end of the range. Higher `skew` means more weight.
+    Long { skew: f64 },
+}
+
+impl IntervalDistribution {
+    // For distributions with unbounded tails, we resample when we land outside the range. If we
+    // keep missing (e.g. because the mean is way outside the range), we give up and clamp.
+    const MAX_ATTEMPTS: usize = 32;
+
+    // Pick a wait between `min` and `max`, inclusive.
+    pub fn sample(&self, rng: &mut impl Rng, min: Duration, max: Duration) -> Duration {
+        let min = min.as_secs_f64();
+        let max = max.as_secs_f64().max(min);
+        let range = max - min;
+
+        let seconds = match *self {
+            Self::Uniform => min + range * rng.gen::<f64>(),
+            Self::Exponential { mean } => {
+                // The exponential distribution starts at the minimum wait, so that's what the
+                // mean is relative to.
+                let scale = (mean - min).max(f64::EPSILON);
+
+                Self::truncated(min, max, || {
+                    // Inverse transform sampling. `1 - u` is in (0, 1], so the log is finite.
+                    min - scale * (1.0 - rng.gen::<f64>()).ln()
+                })
+            }
+            Self::Normal { mean, std_dev } => Self::truncated(min, max, || {
+                // The Box-Muller transform.
+                let u1 = 1.0 - rng.gen::<f64>();
+                let u2 = rng.gen::<f64>();
+                let z = (-2.0 * u1.ln()).sqrt() * (2.0 * std::f64::consts::PI * u2).cos();
+
+                mean + std_dev * z
+            }),
+            Self::Short { skew } => min + range * rng.gen::<f64>().powf(skew.max(1.0)),
+            Self::Long { skew } => max - range * rng.gen::<f64>().powf(skew.max(1.0)),
+        };
+
+        Duration::from_secs_f64(seconds.clamp(min, max))
+    }
+
+    fn truncated(min: f64, max: f64, mut sample: impl FnMut() -> f64) -> f64 {
+        let mut seconds = sample();
+
+        for _ in 1..Self::MAX_ATTEMPTS {
+            if (min..=max).contains(&seconds) {
+                break;
+            }
+
+            seconds = sample();
+        }
+
+        seconds
+    }
+}
+
+// The distributions the user can pick from in the UI, without their parameters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DistributionKind {
+    Uniform,
+    Exponential,
+    Normal,
+    Short,
+    Long,
+}
+
+impl DistributionKind {
+    pub const ALL: [Self; 5] = [
+        Self::Uniform,
+        Self::Exponential,
+        Self::Normal,
+        Self::Short,
+        Self::Long,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Uniform => "uniform",
+            Self::Exponential => "exponential",
+            Self::Normal => "normal",
+            Self::Short => "short",
+            Self::Long => "long",
+        }
+    }
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            Self::Uniform => "Even",
+            Self::Exponential => "Could happen any second",
+            Self::Normal => "Around an average",
+            Self::Short => "Mostly short waits",
+            Self::Long => "Mostly long waits",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|kind| kind.name() == name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::SmallRng, SeedableRng};
+
+    use super::*;
+
+    const SAMPLES: usize = 10_000;
+
+    // Sample in seconds, checking that every sample lands in the range.
+    fn samples(distribution: IntervalDistribution, min: u64, max: u64) -> Vec<f64> {
+        let mut rng = SmallRng::seed_from_u64(0);
+        let min = Duration::from_secs(min);
+        let max = Duration::from_secs(max);
+
+        (0..SAMPLES)
+            .map(|_| {
+                let sample = distribution.sample(&mut rng, min, max);
+                assert!(
+                    (min..=max).contains(&sample),
+                    "{:?} sampled {:?}, which is outside {:?}..={:?}",
+                    distribution,
+                    sample,
+                    min,
+                    max,
+                );
+                sample.as_secs_f64()
+            })
+            .collect()
+    }
+
+    fn mean(samples: &[f64]) -> f64 {
+        samples.iter().sum::<f64>() / samples.len() as f64
+    }
+
+    fn std_dev(samples: &[f64]) -> f64 {
+        let mean = mean(samples);
+        let variance =
+            samples.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / samples.len() as f64;
+        variance.sqrt()
+    }
+
+    fn assert_close(name: &str, actual: f64, expected: f64, tolerance: f64) {
+        assert!(
+            (actual - expected).abs() <= tolerance,
+            "expected the {} to be {} ± {}, but it was {}",
+            name,
+            expected,
+            tolerance,
+            actual,
+        );
+    }
+
+    #[test]
+    fn uniform_is_spread_evenly_over_the_range() {
+        let samples = samples(IntervalDistribution::Uniform, 10, 30);
+
+        assert_close("mean", mean(&samples), 20.0, 0.5);
+        assert_close(
+            "standard deviation",
+            std_dev(&samples),
+            20.0 / 12f64.sqrt(),
+            0.3,
+        );
+    }
+
+    #[test]
+    fn exponential_mean_is_relative_to_the_minimum() {
+        // Starting at 10 seconds with a mean of 15, the scale is 5 seconds, and the standard
+        // deviation of an exponential distribution is the same as its scale.
+        let samples = samples(IntervalDistribution::Exponential { mean: 15.0 }, 10, 100);
+
+        assert_close("mean", mean(&samples), 15.0, 0.3);
+        assert_close("standard deviation", std_dev(&samples), 5.0, 0.3);
+    }
+
+    #[test]
+    fn normal_clusters_around_the_mean() {
+        let samples = samples(
+            IntervalDistribution::Normal {
+                mean: 20.0,
+                std_dev: 3.0,
+            },
+            0,
+            100,
+        );
+
+        assert_close("mean", mean(&samples), 20.0, 0.2);
+        assert_close("standard deviation", std_dev(&samples), 3.0, 0.2);
+    }
+
+    #[test]
+    fn short_and_long_mirror_each_other() {
+        // For `u^3` with `u` uniform on [0, 1], the mean is 1/4 and the variance is
+        // 1/7 - 1/16.
+        let expected_std_dev = 60.0 * (1.0 / 7.0 - 1.0 / 16.0f64).sqrt();
+
+        let short = samples(IntervalDistribution::Short { skew: 3.0 }, 0, 60);
+
+        assert_close("mean", mean(&short), 15.0, 0.6);
+        assert_close("standard deviation", std_dev(&short), expected_std_dev, 0.6);
+
+        let long = samples(IntervalDistribution::Long { skew: 3.0 }, 0, 60);
+
+        assert_close("mean", mean(&long), 45.0, 0.6);
+        assert_close("standard deviation", std_dev(&long), expected_std_dev, 0.6);
+    }
+
+    #[test]
+    fn stays_in_range_when_the_mean_is_outside_it() {
+        samples(IntervalDistribution::Exponential { mean: 1000.0 }, 10, 20);
+        samples(IntervalDistribution::Exponential { mean: 0.0 }, 10, 20);
+
+        samples(
+            IntervalDistribution::Normal {
+                mean: 1000.0,
+                std_dev: 1.0,
+            },
+            10,
+            20,
+        );
+
+        samples(
+            IntervalDistribution::Normal {
+                mean: 0.0,
+                std_dev: 1.0,
+            },
+            10,
+            20,
+        );
+    }
+
+    #[test]
+    fn empty_range_always_gives_the_minimum() {
+        let mut rng = SmallRng::seed_from_u64(0);
+        let secs = Duration::from_secs(15);
+
+        for distribution in [
+            IntervalDistribution::Uniform,
+            IntervalDistribution::Exponential { mean: 20.0 },
+            IntervalDistribution::Normal {
+                mean: 20.0,
+                std_dev: 3.0,
+            },
+            IntervalDistribution::Short { skew: 3.0 },
+            IntervalDistribution::Long { skew: 3.0 },
+        ] {
+            assert_eq!(distribution.sample(&mut rng, secs, secs), secs);
+
+            // A maximum below the minimum is treated as the minimum.
+            assert_eq!(
+                distribution.sample(&mut rng, secs, Duration::from_secs(5)),
+                secs
+            );
+        }
+    }
+}
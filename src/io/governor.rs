@@ -0,0 +1,312 @@
+use std::{
+    collections::VecDeque,
+    fmt,
+    time::{Duration, Instant},
+};
+
+// The safety limits on how much the toy can be used. A limit of zero means there is no limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GovernorLimits {
+    // At most this many fires in any `window`.
+    pub max_fires: u32,
+    pub window: Duration,
+    // The pump can run for at most this long in any minute.
+    pub max_on_time: Duration,
+    // Once a limit is hit, nothing can fire for this long.
+    pub cooldown: Duration,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Limit {
+    Fires,
+    OnTime,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimited {
+    pub limit: Limit,
+    pub retry_after: Duration,
+}
+
+impl fmt::Display for RateLimited {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Round up, so we never tell the user to try again before they actually can.
+        let seconds = self.retry_after.as_secs() + u64::from(self.retry_after.subsec_nanos() > 0);
+
+        match self.limit {
+            Limit::Fires => write!(f, "Too many squirts. Try again in {}s.", seconds),
+            Limit::OnTime => write!(
+                f,
+                "The pump has been running too much. Try again in {}s.",
+                seconds
+            ),
+        }
+    }
+}
+
+impl std::error::Error for RateLimited {}
+
+// Keeps track of how much the toy has been used recently, so that every way of firing it (the
+// remote, auto mode, timers, holds) is held to the same limits. Like the program runner, this
+// never reads the time itself.
+#[derive(Debug)]
+pub struct Governor {
+    limits: GovernorLimits,
+    fires: VecDeque<Instant>,
+    // When each run of the pump ended, and how long it ran for.
+    runs: VecDeque<(Instant, Duration)>,
+    cooldown: Option<(Instant, Limit)>,
+}
+
+impl Governor {
+    const ON_TIME_WINDOW: Duration = Duration::from_secs(60);
+
+    pub fn new(limits: GovernorLimits) -> Self {
+        Self {
+            limits,
+            fires: VecDeque::new(),
+            runs: VecDeque::new(),
+            cooldown: None,
+        }
+    }
+
+    // Forget about anything that's fallen out of its window.
+    fn prune(&mut self, now: Instant) {
+        while self
+            .fires
+            .front()
+            .is_some_and(|fired| now.saturating_duration_since(*fired) >= self.limits.window)
+        {
+            self.fires.pop_front();
+        }
+
+        while self
+            .runs
+            .front()
+            .is_some_and(|(ended, _)| now.saturating_duration_since(*ended) >= Self::ON_TIME_WINDOW)
+        {
+            self.runs.pop_front();
+        }
+
+        if self.cooldown.is_some_and(|(until, _)| now >= until) {
+            self.cooldown = None;
+        }
+    }
+
+    // A run counts toward the limit for a full minute after it ends, so this errs on the side of
+    // caution.
+    fn on_time(&self) -> Duration {
+        self.runs.iter().map(|(_, duration)| *duration).sum()
+    }
+
+    fn trip(&mut self, now: Instant, limit: Limit) -> RateLimited {
+        log::warn!("Hit the {:?} limit. Cooling down.", limit);
+
+        self.cooldown = Some((now + self.limits.cooldown, limit));
+
+        RateLimited {
+            limit,
+            retry_after: self.limits.cooldown,
+        }
+    }
+
+    // Whether the toy is allowed to fire right now.
+    pub fn check(&mut self, now: Instant) -> Result<(), RateLimited> {
+        self.prune(now);
+
+        if let Some((until, limit)) = self.cooldown {
+            return Err(RateLimited {
+                limit,
+                retry_after: until.saturating_duration_since(now),
+            });
+        }
+
+        if self.limits.max_fires > 0 && self.fires.len() >= self.limits.max_fires as usize {
+            return Err(self.trip(now, Limit::Fires));
+        }
+
+        if !self.limits.max_on_time.is_zero() && self.on_time() >= self.limits.max_on_time {
+            return Err(self.trip(now, Limit::OnTime));
+        }
+
+        Ok(())
+    }
+
+    pub fn record_fire(&mut self, now: Instant) {
+        self.fires.push_back(now);
+    }
+
+    // Record that the pump ran for `duration`, ending at `now`.
+    pub fn record_run(&mut self, now: Instant, duration: Duration) {
+        if !duration.is_zero() {
+            self.runs.push_back((now, duration));
+        }
+    }
+
+    // How long until the cooldown is over, or `None` if we're not cooling down.
+    pub fn cooldown(&mut self, now: Instant) -> Option<Duration> {
+        self.prune(now);
+
+        self.cooldown
+            .map(|(until, _)| until.saturating_duration_since(now))
+    }
+
+    // How much longer the pump is allowed to run this minute, or `None` if there's no limit.
+    pub fn remaining_on_time(&mut self, now: Instant) -> Option<Duration> {
+        self.prune(now);
+
+        if self.limits.max_on_time.is_zero() {
+            return None;
+        }
+
+        Some(self.limits.max_on_time.saturating_sub(self.on_time()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secs(secs: u64) -> Duration {
+        Duration::from_secs(secs)
+    }
+
+    fn limits() -> GovernorLimits {
+        GovernorLimits {
+            max_fires: 0,
+            window: Duration::ZERO,
+            max_on_time: Duration::ZERO,
+            cooldown: Duration::ZERO,
+        }
+    }
+
+    #[test]
+    fn zero_limits_mean_no_limit() {
+        let start = Instant::now();
+        let mut governor = Governor::new(limits());
+
+        for _ in 0..100 {
+            governor.record_fire(start);
+            governor.record_run(start, secs(60));
+        }
+
+        assert_eq!(governor.check(start), Ok(()));
+        assert_eq!(governor.remaining_on_time(start), None);
+        assert_eq!(governor.cooldown(start), None);
+    }
+
+    #[test]
+    fn allows_max_fires_per_window() {
+        let start = Instant::now();
+        let mut governor = Governor::new(GovernorLimits {
+            max_fires: 3,
+            window: secs(10),
+            ..limits()
+        });
+
+        for offset in [0, 4, 8] {
+            assert_eq!(governor.check(start + secs(offset)), Ok(()));
+            governor.record_fire(start + secs(offset));
+        }
+
+        assert_eq!(
+            governor.check(start + secs(9)),
+            Err(RateLimited {
+                limit: Limit::Fires,
+                retry_after: Duration::ZERO,
+            })
+        );
+
+        // The first fire falls out of the window, which makes room for one more.
+        assert_eq!(governor.check(start + secs(10)), Ok(()));
+        governor.record_fire(start + secs(10));
+
+        assert!(governor.check(start + secs(11)).is_err());
+    }
+
+    #[test]
+    fn on_time_rolls_off_a_minute_after_each_run() {
+        let start = Instant::now();
+        let mut governor = Governor::new(GovernorLimits {
+            max_on_time: secs(10),
+            ..limits()
+        });
+
+        governor.record_run(start, secs(6));
+
+        assert_eq!(governor.remaining_on_time(start), Some(secs(4)));
+        assert_eq!(governor.check(start), Ok(()));
+
+        governor.record_run(start + secs(30), secs(4));
+
+        assert_eq!(
+            governor.remaining_on_time(start + secs(30)),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(
+            governor.check(start + secs(30)),
+            Err(RateLimited {
+                limit: Limit::OnTime,
+                retry_after: Duration::ZERO,
+            })
+        );
+
+        // A minute after the first run ended, only the second one still counts.
+        assert_eq!(governor.remaining_on_time(start + secs(60)), Some(secs(6)));
+        assert_eq!(governor.check(start + secs(60)), Ok(()));
+
+        assert_eq!(governor.remaining_on_time(start + secs(90)), Some(secs(10)));
+    }
+
+    #[test]
+    fn cooldown_ends_exactly_when_it_runs_out() {
+        let start = Instant::now();
+        let mut governor = Governor::new(GovernorLimits {
+            max_fires: 1,
+            window: secs(60),
+            cooldown: secs(5),
+            ..limits()
+        });
+
+        governor.record_fire(start);
+
+        assert_eq!(governor.cooldown(start), None);
+        assert_eq!(
+            governor.check(start),
+            Err(RateLimited {
+                limit: Limit::Fires,
+                retry_after: secs(5),
+            })
+        );
+
+        assert_eq!(governor.cooldown(start), Some(secs(5)));
+        assert_eq!(
+            governor.cooldown(start + Duration::from_millis(4999)),
+            Some(Duration::from_millis(1))
+        );
+
+        // While cooling down, nothing else gets counted against the user.
+        assert_eq!(
+            governor.check(start + secs(2)),
+            Err(RateLimited {
+                limit: Limit::Fires,
+                retry_after: secs(3),
+            })
+        );
+
+        assert_eq!(governor.cooldown(start + secs(5)), None);
+    }
+
+    #[test]
+    fn rounds_the_retry_time_up() {
+        let rate_limited = RateLimited {
+            limit: Limit::Fires,
+            retry_after: Duration::from_millis(1500),
+        };
+
+        assert_eq!(
+            rate_limited.to_string(),
+            "Too many squirts. Try again in 2s."
+        );
+    }
+}
@@ -0,0 +1,86 @@
+use std::fmt;
+
+use esp_idf_svc::hal::i2c::I2cDriver;
+
+use super::driver::PumpDriver;
+
+pub struct I2cPumpDriver<'d> {
+    driver: I2cDriver<'d>,
+    address: u8,
+    timeout: u32,
+    stop_message: Vec<u8>,
+}
+
+impl fmt::Debug for I2cPumpDriver<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("I2cPumpDriver")
+            .field("address", &self.address)
+            .field("timeout", &self.timeout)
+            .field("stop_message", &self.stop_message)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<'d> I2cPumpDriver<'d> {
+    pub fn new(driver: I2cDriver<'d>, address: u8, timeout: u32, stop_message: Vec<u8>) -> Self {
+        Self {
+            driver,
+            address,
+            timeout,
+            stop_message,
+        }
+    }
+}
+
+impl PumpDriver for I2cPumpDriver<'_> {
+    fn fire(&mut self, message: &[u8]) -> anyhow::Result<()> {
+        log::info!(
+            "Activating the pump over I2C at address {:#04x} with message {:?}.",
+            self.address,
+            message,
+        );
+
+        self.driver.write(self.address, message, self.timeout)?;
+
+        Ok(())
+    }
+
+    fn stop(&mut self) -> anyhow::Result<()> {
+        // Not every pump controller needs to be told to stop; some just run for a fixed amount of
+        // time each time they're triggered.
+        if self.stop_message.is_empty() {
+            return Ok(());
+        }
+
+        log::info!(
+            "Stopping the pump over I2C at address {:#04x} with message {:?}.",
+            self.address,
+            self.stop_message,
+        );
+
+        self.driver
+            .write(self.address, &self.stop_message, self.timeout)?;
+
+        Ok(())
+    }
+
+    fn stop_with(&mut self, message: &[u8]) -> anyhow::Result<()> {
+        log::info!(
+            "Stopping the pump over I2C at address {:#04x} with message {:?}.",
+            self.address,
+            message,
+        );
+
+        self.driver.write(self.address, message, self.timeout)?;
+
+        Ok(())
+    }
+
+    fn health_check(&mut self) -> anyhow::Result<()> {
+        // An empty write is just the address byte, so this only succeeds if the controller ACKs
+        // its address.
+        self.driver.write(self.address, &[], self.timeout)?;
+
+        Ok(())
+    }
+}
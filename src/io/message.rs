@@ -0,0 +1,51 @@
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+// A message to send to the pump controller, where some of the bytes may be placeholders that are
+// filled in each time the toy fires. For example:
+//
+// ```toml
+// message = [0x10, "{intensity}", "{duration_ms_hi}", "{duration_ms_lo}"]
+// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct MessageTemplate(Vec<MessageByte>);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum MessageByte {
+    Literal(u8),
+    Placeholder(Placeholder),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Placeholder {
+    #[serde(rename = "{intensity}")]
+    Intensity,
+    // The duration is sent as a big-endian 16-bit number of milliseconds, split across two bytes.
+    #[serde(rename = "{duration_ms_hi}")]
+    DurationMsHi,
+    #[serde(rename = "{duration_ms_lo}")]
+    DurationMsLo,
+}
+
+impl MessageTemplate {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn render(&self, intensity: u8, duration: Duration) -> Vec<u8> {
+        let duration_ms = u16::try_from(duration.as_millis()).unwrap_or(u16::MAX);
+
+        self.0
+            .iter()
+            .map(|byte| match byte {
+                MessageByte::Literal(value) => *value,
+                MessageByte::Placeholder(Placeholder::Intensity) => intensity,
+                MessageByte::Placeholder(Placeholder::DurationMsHi) => (duration_ms >> 8) as u8,
+                MessageByte::Placeholder(Placeholder::DurationMsLo) => duration_ms as u8,
+            })
+            .collect()
+    }
+}
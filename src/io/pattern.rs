@@ -0,0 +1,512 @@
+use std::time::Duration;
+
+use anyhow::bail;
+use serde::{Deserialize, Serialize};
+
+use super::message::MessageTemplate;
+
+// A pattern is a sequence of steps that's played back each time the toy fires. This is what
+// allows a single trigger to produce a burst of squirts or a rhythm rather than just one.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Pattern {
+    pub name: String,
+    pub steps: Vec<Step>,
+}
+
+// All times are in milliseconds, to match the rest of the `[io]` config.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Step {
+    // Overrides `io.message` for this step.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message: Option<MessageTemplate>,
+    // The intensity of this step, relative to the intensity the toy was fired with. 255 is the
+    // full intensity.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub intensity: Option<u8>,
+    // How long the pump should run for. If this is zero, we use the duration the toy was fired
+    // with, if any. Otherwise, the pump controller decides how long to run and we never send it a
+    // stop message.
+    #[serde(default)]
+    pub duration: u32,
+    // How long to wait after this step before moving on to the next one.
+    #[serde(default)]
+    pub pause: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Action {
+    Fire(Vec<u8>),
+    Stop,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Event {
+    // The offset from the start of the pattern.
+    pub at: Duration,
+    pub action: Action,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Timeline {
+    pub events: Vec<Event>,
+    // The total time it takes to play back the pattern, including the pause after the last step.
+    pub duration: Duration,
+}
+
+impl Timeline {
+    // How long the pump runs for in total. Steps where the pump controller decides how long to
+    // run don't count, since we have no way of knowing.
+    pub fn on_time(&self) -> Duration {
+        let mut on_time = Duration::ZERO;
+        let mut started = None;
+
+        for event in &self.events {
+            match event.action {
+                Action::Fire(_) => started = Some(event.at),
+                Action::Stop => {
+                    if let Some(started) = started.take() {
+                        on_time += event.at.saturating_sub(started);
+                    }
+                }
+            }
+        }
+
+        on_time
+    }
+
+    // How long to count this timeline against the governor's limit on how long the pump can run.
+    // Unlike `on_time`, this assumes the pump keeps running after a fire until it's told to do
+    // something else, or until the `block_time` after the end of the timeline, which is as long as
+    // we wait for the pump controller before accepting another request.
+    pub fn charged_time(&self, block_time: Duration) -> Duration {
+        let mut charged_time = Duration::ZERO;
+        let mut started = None;
+
+        for event in &self.events {
+            if let Some(started) = started.take() {
+                charged_time += event.at.saturating_sub(started);
+            }
+
+            if let Action::Fire(_) = event.action {
+                started = Some(event.at);
+            }
+        }
+
+        if let Some(started) = started {
+            charged_time += self.duration.saturating_sub(started) + block_time;
+        }
+
+        charged_time
+    }
+}
+
+impl Pattern {
+    pub const DEFAULT_NAME: &'static str = "single";
+
+    // These limits keep a pattern from hogging the pump (or NVS) indefinitely.
+    const MAX_NAME_LEN: usize = 32;
+    const MAX_STEPS: usize = 32;
+    const MAX_DURATION: Duration = Duration::from_secs(60);
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.name.is_empty() || self.name.len() > Self::MAX_NAME_LEN {
+            bail!(
+                "Pattern names must be between 1 and {} characters.",
+                Self::MAX_NAME_LEN
+            );
+        }
+
+        // Pattern names end up in the UI, so we keep them boring.
+        if !self
+            .name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == ' ' || c == '-' || c == '_')
+        {
+            bail!(
+                "Pattern names may only contain letters, numbers, spaces, dashes, and underscores."
+            );
+        }
+
+        if self.steps.is_empty() || self.steps.len() > Self::MAX_STEPS {
+            bail!(
+                "Patterns must have between 1 and {} steps.",
+                Self::MAX_STEPS
+            );
+        }
+
+        if self.duration() > Self::MAX_DURATION {
+            bail!(
+                "Patterns must not be longer than {}s.",
+                Self::MAX_DURATION.as_secs()
+            );
+        }
+
+        Ok(())
+    }
+
+    // The time it takes to play back this pattern, not counting steps that take their duration
+    // from whatever triggered the toy.
+    pub fn duration(&self) -> Duration {
+        self.steps
+            .iter()
+            .map(|step| Duration::from_millis(u64::from(step.duration) + u64::from(step.pause)))
+            .sum()
+    }
+
+    // Flatten the steps into a list of timed actions for the pump driver. The `default_message`
+    // is used for steps that don't specify their own.
+    pub fn timeline(
+        &self,
+        default_message: &MessageTemplate,
+        intensity: u8,
+        default_duration: Duration,
+    ) -> Timeline {
+        let mut events = Vec::with_capacity(self.steps.len() * 2);
+        let mut at = Duration::ZERO;
+
+        for step in &self.steps {
+            let duration = if step.duration > 0 {
+                Duration::from_millis(step.duration.into())
+            } else {
+                default_duration
+            };
+
+            let intensity = match step.intensity {
+                Some(relative) => (u16::from(intensity) * u16::from(relative) / 255) as u8,
+                None => intensity,
+            };
+
+            let message = step.message.as_ref().unwrap_or(default_message);
+
+            events.push(Event {
+                at,
+                action: Action::Fire(message.render(intensity, duration)),
+            });
+
+            if !duration.is_zero() {
+                at += duration;
+
+                events.push(Event {
+                    at,
+                    action: Action::Stop,
+                });
+            }
+
+            at += Duration::from_millis(step.pause.into());
+        }
+
+        Timeline {
+            events,
+            duration: at,
+        }
+    }
+}
+
+fn step(duration: u32, pause: u32) -> Step {
+    Step {
+        message: None,
+        intensity: None,
+        duration,
+        pause,
+    }
+}
+
+fn step_at(intensity: u8, duration: u32, pause: u32) -> Step {
+    Step {
+        intensity: Some(intensity),
+        ..step(duration, pause)
+    }
+}
+
+// The patterns that are always available, regardless of what's in the config file or NVS.
+pub fn builtin_patterns() -> Vec<Pattern> {
+    vec![
+        Pattern {
+            name: String::from(Pattern::DEFAULT_NAME),
+            steps: vec![step(0, 0)],
+        },
+        Pattern {
+            name: String::from("double"),
+            steps: vec![step(0, 500), step(0, 0)],
+        },
+        Pattern {
+            name: String::from("burst"),
+            steps: vec![step(250, 250), step(250, 250), step(250, 0)],
+        },
+        Pattern {
+            name: String::from("pulse"),
+            steps: vec![
+                step(100, 300),
+                step(100, 300),
+                step(100, 300),
+                step(100, 300),
+                step(100, 0),
+            ],
+        },
+        Pattern {
+            name: String::from("long-short"),
+            steps: vec![step(1000, 500), step(250, 0)],
+        },
+        Pattern {
+            name: String::from("wave"),
+            steps: vec![
+                step_at(64, 200, 100),
+                step_at(128, 200, 100),
+                step_at(192, 200, 100),
+                step_at(255, 200, 100),
+                step_at(192, 200, 100),
+                step_at(128, 200, 100),
+                step_at(64, 200, 0),
+            ],
+        },
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    fn builtin(name: &str) -> Pattern {
+        builtin_patterns()
+            .into_iter()
+            .find(|pattern| pattern.name == name)
+            .unwrap()
+    }
+
+    fn message() -> MessageTemplate {
+        serde_json::from_str(r#"[16, "{intensity}"]"#).unwrap()
+    }
+
+    fn fire(at: u64, intensity: u8) -> Event {
+        Event {
+            at: ms(at),
+            action: Action::Fire(vec![16, intensity]),
+        }
+    }
+
+    fn stop(at: u64) -> Event {
+        Event {
+            at: ms(at),
+            action: Action::Stop,
+        }
+    }
+
+    #[test]
+    fn plays_burst_back_in_order() {
+        let timeline = builtin("burst").timeline(&message(), 100, Duration::ZERO);
+
+        assert_eq!(
+            timeline.events,
+            vec![
+                fire(0, 100),
+                stop(250),
+                fire(500, 100),
+                stop(750),
+                fire(1000, 100),
+                stop(1250),
+            ]
+        );
+        assert_eq!(timeline.duration, ms(1250));
+    }
+
+    #[test]
+    fn plays_pulse_back_in_order() {
+        let timeline = builtin("pulse").timeline(&message(), 100, Duration::ZERO);
+
+        let expected = (0..5)
+            .flat_map(|index| [fire(index * 400, 100), stop(index * 400 + 100)])
+            .collect::<Vec<_>>();
+
+        assert_eq!(timeline.events, expected);
+        assert_eq!(timeline.duration, ms(1700));
+    }
+
+    #[test]
+    fn plays_wave_back_in_order() {
+        let timeline = builtin("wave").timeline(&message(), 255, Duration::ZERO);
+
+        let expected = [64, 128, 192, 255, 192, 128, 64]
+            .into_iter()
+            .zip(0..)
+            .flat_map(|(intensity, index)| [fire(index * 300, intensity), stop(index * 300 + 200)])
+            .collect::<Vec<_>>();
+
+        assert_eq!(timeline.events, expected);
+        assert_eq!(timeline.duration, ms(2000));
+    }
+
+    #[test]
+    fn steps_without_a_duration_use_the_default() {
+        let timeline = builtin("double").timeline(&message(), 100, ms(200));
+
+        assert_eq!(
+            timeline.events,
+            vec![fire(0, 100), stop(200), fire(700, 100), stop(900)]
+        );
+        assert_eq!(timeline.duration, ms(900));
+    }
+
+    #[test]
+    fn steps_without_any_duration_never_stop() {
+        let timeline = builtin(Pattern::DEFAULT_NAME).timeline(&message(), 100, Duration::ZERO);
+
+        assert_eq!(timeline.events, vec![fire(0, 100)]);
+        assert_eq!(timeline.duration, Duration::ZERO);
+    }
+
+    #[test]
+    fn scales_step_intensity_relative_to_the_fire() {
+        let pattern = Pattern {
+            name: String::from("scaled"),
+            steps: vec![
+                step_at(0, 100, 0),
+                step_at(128, 100, 0),
+                step_at(255, 100, 0),
+            ],
+        };
+
+        let events = |intensity| {
+            pattern
+                .timeline(&message(), intensity, Duration::ZERO)
+                .events
+                .into_iter()
+                .filter_map(|event| match event.action {
+                    Action::Fire(message) => Some(message[1]),
+                    Action::Stop => None,
+                })
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(events(200), vec![0, 100, 200]);
+        // Full intensity on both sides doesn't overflow.
+        assert_eq!(events(255), vec![0, 128, 255]);
+        assert_eq!(events(0), vec![0, 0, 0]);
+    }
+
+    #[test]
+    fn steps_can_override_the_message() {
+        let pattern = Pattern {
+            name: String::from("custom"),
+            steps: vec![Step {
+                message: Some(serde_json::from_str("[1, 2, 3]").unwrap()),
+                ..step(100, 0)
+            }],
+        };
+
+        let timeline = pattern.timeline(&message(), 100, Duration::ZERO);
+
+        assert_eq!(timeline.events[0].action, Action::Fire(vec![1, 2, 3]));
+    }
+
+    #[test]
+    fn on_time_only_counts_from_fire_to_stop() {
+        assert_eq!(
+            builtin("burst")
+                .timeline(&message(), 100, Duration::ZERO)
+                .on_time(),
+            ms(750)
+        );
+
+        // The pump controller decides how long these run, so we can't count them.
+        assert_eq!(
+            builtin(Pattern::DEFAULT_NAME)
+                .timeline(&message(), 100, Duration::ZERO)
+                .on_time(),
+            Duration::ZERO
+        );
+
+        let timeline = Timeline {
+            events: vec![
+                stop(0),
+                fire(100, 100),
+                fire(200, 100),
+                stop(300),
+                stop(400),
+                fire(500, 100),
+            ],
+            duration: ms(500),
+        };
+
+        assert_eq!(timeline.on_time(), ms(100));
+    }
+
+    #[test]
+    fn charges_fires_without_a_stop_until_the_pump_is_free() {
+        let block_time = ms(1000);
+
+        // Patterns with a stop for every fire are charged exactly.
+        assert_eq!(
+            builtin("burst")
+                .timeline(&message(), 100, Duration::ZERO)
+                .charged_time(block_time),
+            ms(750)
+        );
+
+        assert_eq!(
+            builtin(Pattern::DEFAULT_NAME)
+                .timeline(&message(), 100, Duration::ZERO)
+                .charged_time(block_time),
+            block_time
+        );
+
+        // The first fire runs until the second one, and the second one until the block time is up.
+        assert_eq!(
+            builtin("double")
+                .timeline(&message(), 100, Duration::ZERO)
+                .charged_time(block_time),
+            ms(1500)
+        );
+    }
+
+    #[test]
+    fn builtin_patterns_are_valid() {
+        for pattern in builtin_patterns() {
+            assert!(pattern.validate().is_ok(), "{:?}", pattern.name);
+        }
+    }
+
+    #[test]
+    fn rejects_bad_patterns() {
+        let valid = Pattern {
+            name: String::from("my pattern-1_a"),
+            steps: vec![step(100, 100)],
+        };
+
+        assert!(valid.validate().is_ok());
+
+        let invalid = [
+            Pattern {
+                name: String::new(),
+                ..valid.clone()
+            },
+            Pattern {
+                name: "a".repeat(Pattern::MAX_NAME_LEN + 1),
+                ..valid.clone()
+            },
+            Pattern {
+                name: String::from("<script>"),
+                ..valid.clone()
+            },
+            Pattern {
+                steps: Vec::new(),
+                ..valid.clone()
+            },
+            Pattern {
+                steps: vec![step(10, 10); Pattern::MAX_STEPS + 1],
+                ..valid.clone()
+            },
+            Pattern {
+                steps: vec![step(30_000, 30_000), step(1, 0)],
+                ..valid.clone()
+            },
+        ];
+
+        for pattern in invalid {
+            assert!(pattern.validate().is_err(), "{:?}", pattern);
+        }
+    }
+}
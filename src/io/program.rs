@@ -0,0 +1,238 @@
+use std::time::{Duration, Instant};
+
+use anyhow::bail;
+use serde::{Deserialize, Serialize};
+
+// A program is a session that changes over time. It's made up of phases, each with its own range
+// of waits between squirts, and optionally its own pattern and intensity. For example, a program
+// might start with sparse squirts, get more and more frequent, and then end with a final burst.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Program {
+    pub name: String,
+    pub phases: Vec<Phase>,
+}
+
+// All times are in seconds, to match the `[frequency]` config.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Phase {
+    pub duration: u32,
+    pub min_freq: u32,
+    pub max_freq: u32,
+    // Overrides the pattern the user has selected.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pattern: Option<String>,
+    // Overrides the intensity the toy would otherwise be fired with.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub intensity: Option<u8>,
+    // How to get from this phase to the next one.
+    #[serde(default)]
+    pub transition: Transition,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Transition {
+    // Jump straight to the next phase's settings when this phase ends.
+    #[default]
+    Stepped,
+    // Gradually move toward the next phase's settings over the course of this phase.
+    Linear,
+}
+
+// Where a program is at a given point in time.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PhaseState {
+    pub index: usize,
+    pub min_freq: Duration,
+    pub max_freq: Duration,
+    pub pattern: Option<String>,
+    pub intensity: Option<u8>,
+    // The time left in this phase.
+    pub phase_remaining: Duration,
+    // The time left in the whole program.
+    pub remaining: Duration,
+}
+
+fn lerp(from: f64, to: f64, progress: f64) -> f64 {
+    from + (to - from) * progress
+}
+
+impl Phase {
+    fn duration(&self) -> Duration {
+        Duration::from_secs(self.duration.into())
+    }
+}
+
+impl Program {
+    // These limits keep programs from taking up too much space in NVS.
+    const MAX_NAME_LEN: usize = 32;
+    const MAX_PHASES: usize = 16;
+    const MAX_DURATION: Duration = Duration::from_secs(24 * 60 * 60);
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.name.is_empty() || self.name.len() > Self::MAX_NAME_LEN {
+            bail!(
+                "Program names must be between 1 and {} characters.",
+                Self::MAX_NAME_LEN
+            );
+        }
+
+        // Program names end up in the UI, so we keep them boring.
+        if !self
+            .name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == ' ' || c == '-' || c == '_')
+        {
+            bail!(
+                "Program names may only contain letters, numbers, spaces, dashes, and underscores."
+            );
+        }
+
+        if self.phases.is_empty() || self.phases.len() > Self::MAX_PHASES {
+            bail!(
+                "Programs must have between 1 and {} phases.",
+                Self::MAX_PHASES
+            );
+        }
+
+        for phase in &self.phases {
+            if phase.duration == 0 {
+                bail!("Phases must be longer than 0s.");
+            }
+
+            if phase.min_freq > phase.max_freq {
+                bail!("The minimum wait in a phase must not be longer than the maximum wait.");
+            }
+        }
+
+        if self.duration() > Self::MAX_DURATION {
+            bail!(
+                "Programs must not be longer than {}h.",
+                Self::MAX_DURATION.as_secs() / 60 / 60
+            );
+        }
+
+        Ok(())
+    }
+
+    pub fn duration(&self) -> Duration {
+        self.phases.iter().map(Phase::duration).sum()
+    }
+
+    // Where the program is at `elapsed` time after it started, or `None` if it's over.
+    pub fn at(&self, elapsed: Duration) -> Option<PhaseState> {
+        let total = self.duration();
+        let mut start = Duration::ZERO;
+
+        for (index, phase) in self.phases.iter().enumerate() {
+            let end = start + phase.duration();
+
+            if elapsed >= end {
+                start = end;
+                continue;
+            }
+
+            let progress = (elapsed - start).as_secs_f64() / phase.duration().as_secs_f64();
+
+            let (min_freq, max_freq, intensity) =
+                match (phase.transition, self.phases.get(index + 1)) {
+                    (Transition::Linear, Some(next)) => (
+                        lerp(phase.min_freq.into(), next.min_freq.into(), progress),
+                        lerp(phase.max_freq.into(), next.max_freq.into(), progress),
+                        match (phase.intensity, next.intensity) {
+                            (Some(from), Some(to)) => {
+                                Some(lerp(from.into(), to.into(), progress).round() as u8)
+                            }
+                            (intensity, _) => intensity,
+                        },
+                    ),
+                    _ => (
+                        phase.min_freq.into(),
+                        phase.max_freq.into(),
+                        phase.intensity,
+                    ),
+                };
+
+            return Some(PhaseState {
+                index,
+                min_freq: Duration::from_secs_f64(min_freq),
+                max_freq: Duration::from_secs_f64(max_freq),
+                pattern: phase.pattern.clone(),
+                intensity,
+                phase_remaining: end - elapsed,
+                remaining: total - elapsed,
+            });
+        }
+
+        None
+    }
+}
+
+// A program that's currently running. The time spent paused doesn't count toward the program's
+// progress.
+#[derive(Debug, Clone)]
+pub struct ProgramRun {
+    program: Program,
+    started: Instant,
+    paused: Option<Instant>,
+}
+
+impl ProgramRun {
+    pub fn new(program: Program, now: Instant) -> Self {
+        Self {
+            program,
+            started: now,
+            paused: None,
+        }
+    }
+
+    pub fn program(&self) -> &Program {
+        &self.program
+    }
+
+    pub fn pause(&mut self, now: Instant) {
+        self.paused.get_or_insert(now);
+    }
+
+    pub fn resume(&mut self, now: Instant) {
+        if let Some(paused) = self.paused.take() {
+            self.started += now.saturating_duration_since(paused);
+        }
+    }
+
+    pub fn elapsed(&self, now: Instant) -> Duration {
+        self.paused
+            .unwrap_or(now)
+            .saturating_duration_since(self.started)
+    }
+
+    pub fn state(&self, now: Instant) -> Option<PhaseState> {
+        self.program.at(self.elapsed(now))
+    }
+}
+
+fn phase(duration: u32, min_freq: u32, max_freq: u32, transition: Transition) -> Phase {
+    Phase {
+        duration,
+        min_freq,
+        max_freq,
+        pattern: None,
+        intensity: None,
+        transition,
+    }
+}
+
+// The programs that are always available, regardless of what's in the config file or NVS.
+pub fn builtin_programs() -> Vec<Program> {
+    vec![Program {
+        name: String::from("warm-up"),
+        phases: vec![
+            phase(10 * 60, 60, 180, Transition::Stepped),
+            phase(10 * 60, 30, 90, Transition::Linear),
+            Phase {
+                pattern: Some(String::from("burst")),
+                ..phase(60, 5, 15, Transition::Stepped)
+            },
+        ],
+    }]
+}
@@ -0,0 +1,277 @@
+use std::time::{Duration, Instant};
+
+// The scheduler never reads the time directly, so that its timing can be tested without waiting
+// around for real.
+pub trait Clock {
+    fn now(&self) -> Instant;
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Stopped,
+    Waiting {
+        started: Instant,
+        interval: Duration,
+    },
+    Paused {
+        elapsed: Duration,
+        interval: Duration,
+    },
+}
+
+// Keeps track of when auto mode should fire next. This doesn't do any waiting itself; the caller
+// is expected to wait for up to `timeout()` for the next command, and then check `is_due()`.
+#[derive(Debug)]
+pub struct Scheduler<C> {
+    clock: C,
+    state: State,
+}
+
+impl<C: Clock> Scheduler<C> {
+    pub fn new(clock: C) -> Self {
+        Self {
+            clock,
+            state: State::Stopped,
+        }
+    }
+
+    // Start a new countdown, replacing the current one if there is one.
+    pub fn start(&mut self, interval: Duration) {
+        self.state = State::Waiting {
+            started: self.clock.now(),
+            interval,
+        };
+    }
+
+    pub fn stop(&mut self) {
+        self.state = State::Stopped;
+    }
+
+    pub fn pause(&mut self) {
+        if let State::Waiting { started, interval } = self.state {
+            self.state = State::Paused {
+                elapsed: self.clock.now().saturating_duration_since(started),
+                interval,
+            };
+        }
+    }
+
+    pub fn resume(&mut self) {
+        if let State::Paused { elapsed, interval } = self.state {
+            let now = self.clock.now();
+
+            self.state = State::Waiting {
+                started: now.checked_sub(elapsed).unwrap_or(now),
+                interval,
+            };
+        }
+    }
+
+    // Swap out the interval of the current countdown without losing the time that's already
+    // elapsed. If the new interval is shorter than the time that's already elapsed, the countdown
+    // is due immediately.
+    pub fn reschedule(&mut self, new_interval: Duration) {
+        match &mut self.state {
+            State::Waiting { interval, .. } | State::Paused { interval, .. } => {
+                *interval = new_interval;
+            }
+            State::Stopped => {}
+        }
+    }
+
+    pub fn is_running(&self) -> bool {
+        !matches!(self.state, State::Stopped)
+    }
+
+    // When the countdown is due, or `None` if there's no countdown running.
+    pub fn deadline(&self) -> Option<Instant> {
+        match self.state {
+            State::Waiting { started, interval } => Some(started + interval),
+            State::Stopped | State::Paused { .. } => None,
+        }
+    }
+
+    // How long until the countdown is due, or `None` if there's no countdown running.
+    pub fn timeout(&self) -> Option<Duration> {
+        self.deadline()
+            .map(|deadline| deadline.saturating_duration_since(self.clock.now()))
+    }
+
+    pub fn is_due(&self) -> bool {
+        self.timeout() == Some(Duration::ZERO)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::Cell, rc::Rc};
+
+    use super::*;
+
+    // A clock that only moves when the test tells it to.
+    #[derive(Debug, Clone)]
+    struct FakeClock(Rc<Cell<Instant>>);
+
+    impl FakeClock {
+        fn new() -> Self {
+            Self(Rc::new(Cell::new(Instant::now())))
+        }
+
+        fn advance(&self, duration: Duration) {
+            self.0.set(self.0.get() + duration);
+        }
+    }
+
+    impl Clock for FakeClock {
+        fn now(&self) -> Instant {
+            self.0.get()
+        }
+    }
+
+    fn secs(secs: u64) -> Duration {
+        Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn fires_once_the_interval_has_elapsed() {
+        let clock = FakeClock::new();
+        let mut scheduler = Scheduler::new(clock.clone());
+
+        scheduler.start(secs(10));
+
+        assert_eq!(scheduler.deadline(), Some(clock.now() + secs(10)));
+        assert_eq!(scheduler.timeout(), Some(secs(10)));
+        assert!(!scheduler.is_due());
+
+        clock.advance(secs(9));
+
+        assert_eq!(scheduler.timeout(), Some(secs(1)));
+        assert!(!scheduler.is_due());
+
+        clock.advance(secs(1));
+
+        assert_eq!(scheduler.timeout(), Some(Duration::ZERO));
+        assert!(scheduler.is_due());
+
+        // It stays due until the caller does something about it.
+        clock.advance(secs(5));
+
+        assert!(scheduler.is_due());
+    }
+
+    #[test]
+    fn starting_again_replaces_the_countdown() {
+        let clock = FakeClock::new();
+        let mut scheduler = Scheduler::new(clock.clone());
+
+        scheduler.start(secs(10));
+        clock.advance(secs(8));
+        scheduler.start(secs(10));
+
+        assert_eq!(scheduler.timeout(), Some(secs(10)));
+    }
+
+    #[test]
+    fn never_fires_once_stopped() {
+        let clock = FakeClock::new();
+        let mut scheduler = Scheduler::new(clock.clone());
+
+        assert!(!scheduler.is_running());
+
+        scheduler.start(secs(10));
+        clock.advance(secs(5));
+        scheduler.stop();
+
+        assert!(!scheduler.is_running());
+        assert_eq!(scheduler.deadline(), None);
+        assert_eq!(scheduler.timeout(), None);
+
+        clock.advance(secs(60));
+
+        assert!(!scheduler.is_due());
+    }
+
+    #[test]
+    fn pausing_holds_the_elapsed_time() {
+        let clock = FakeClock::new();
+        let mut scheduler = Scheduler::new(clock.clone());
+
+        scheduler.start(secs(10));
+        clock.advance(secs(4));
+        scheduler.pause();
+
+        assert!(scheduler.is_running());
+        assert_eq!(scheduler.timeout(), None);
+
+        clock.advance(secs(60));
+
+        assert!(!scheduler.is_due());
+
+        scheduler.resume();
+
+        assert_eq!(scheduler.timeout(), Some(secs(6)));
+    }
+
+    #[test]
+    fn rescheduling_keeps_the_elapsed_time() {
+        let clock = FakeClock::new();
+        let mut scheduler = Scheduler::new(clock.clone());
+
+        scheduler.start(secs(10));
+        clock.advance(secs(4));
+        scheduler.reschedule(secs(20));
+
+        assert_eq!(scheduler.timeout(), Some(secs(16)));
+
+        scheduler.reschedule(secs(6));
+
+        assert_eq!(scheduler.timeout(), Some(secs(2)));
+    }
+
+    #[test]
+    fn rescheduling_shorter_than_the_elapsed_time_is_due_immediately() {
+        let clock = FakeClock::new();
+        let mut scheduler = Scheduler::new(clock.clone());
+
+        scheduler.start(secs(10));
+        clock.advance(secs(8));
+        scheduler.reschedule(secs(5));
+
+        assert!(scheduler.is_due());
+    }
+
+    #[test]
+    fn rescheduling_while_paused_applies_on_resume() {
+        let clock = FakeClock::new();
+        let mut scheduler = Scheduler::new(clock.clone());
+
+        scheduler.start(secs(10));
+        clock.advance(secs(4));
+        scheduler.pause();
+        scheduler.reschedule(secs(30));
+        clock.advance(secs(60));
+        scheduler.resume();
+
+        assert_eq!(scheduler.timeout(), Some(secs(26)));
+    }
+
+    #[test]
+    fn rescheduling_while_stopped_does_nothing() {
+        let clock = FakeClock::new();
+        let mut scheduler = Scheduler::new(clock.clone());
+
+        scheduler.reschedule(secs(10));
+
+        assert!(!scheduler.is_running());
+        assert_eq!(scheduler.timeout(), None);
+    }
+}
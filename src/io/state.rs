@@ -0,0 +1,662 @@
+use std::time::{Duration, Instant};
+
+use anyhow::bail;
+use serde::Serialize;
+
+use super::governor::{Governor, GovernorLimits, RateLimited};
+
+// What happened to a request to run the pump.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Accepted,
+    // The toy was already doing something, so the request was dropped.
+    Busy,
+    RateLimited(RateLimited),
+    // Nothing can fire until someone arms the toy.
+    Disarmed,
+    // The wearer used the safeword, and nothing can fire or be armed for this much longer.
+    LockedOut(Duration),
+    // The request was accepted, but there's no pump controller to act on it.
+    TestMode,
+}
+
+// What the toy is doing. When more than one of these is true at once (e.g. the pump is running a
+// squirt from auto mode), the one listed first wins.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum State {
+    LockedOut,
+    Disarmed,
+    Firing,
+    Cooldown,
+    Paused,
+    Auto,
+    Idle,
+}
+
+// How the safeword works.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SafewordConfig {
+    // How long everything stays locked after the safeword is used.
+    pub lockout: Duration,
+    // The PIN that lifts the lockout early. Without one, the lockout can't be lifted early.
+    pub pin: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Lockout {
+    until: Instant,
+    // The PIN is short, so we don't let anyone keep guessing at it.
+    failed_attempts: u32,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+enum Mode {
+    #[default]
+    Manual,
+    Auto,
+    Paused,
+}
+
+// Everything the state machine knows, taken at a single point in time so that none of it can
+// disagree.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Snapshot {
+    pub state: State,
+    pub is_armed: bool,
+    pub is_auto: bool,
+    pub is_paused: bool,
+    pub is_firing: bool,
+    // How long until the safeword lockout ends, if there is one.
+    pub lockout: Option<Duration>,
+    // How long until the toy can fire again, if the governor has tripped.
+    pub cooldown: Option<Duration>,
+    // How long until the toy disarms itself for being idle, if it's counting down.
+    pub idle_remaining: Option<Duration>,
+}
+
+// Decides whether each request to run the pump goes through, and keeps track of what the toy is
+// doing. It doesn't act on anything itself; the pump and auto mode threads do what it decides and
+// report back. Like the governor, it never reads the time itself.
+#[derive(Debug)]
+pub struct StateMachine {
+    governor: Governor,
+    mode: Mode,
+    // The toy starts out disarmed, and once it's disarmed, it stays that way until someone
+    // deliberately arms it.
+    is_armed: bool,
+    // The last time anything happened while the toy was armed.
+    last_activity: Option<Instant>,
+    // If nothing happens for this long, the toy disarms itself. Zero means it never does.
+    idle_timeout: Duration,
+    // The last time the remote let us know it was still there.
+    last_heartbeat: Option<Instant>,
+    // If the remote goes quiet for this long while the toy is doing something, we assume it's gone
+    // and stop. Zero means we never do.
+    heartbeat_timeout: Duration,
+    safeword: SafewordConfig,
+    lockout: Option<Lockout>,
+    // Whether the pump thread is working on a request.
+    is_firing: bool,
+    // Whether the pump driver is the null driver, which doesn't actually run the pump.
+    is_test_mode: bool,
+}
+
+impl StateMachine {
+    const MAX_PIN_ATTEMPTS: u32 = 5;
+
+    pub fn new(
+        limits: GovernorLimits,
+        idle_timeout: Duration,
+        heartbeat_timeout: Duration,
+        safeword: SafewordConfig,
+        is_test_mode: bool,
+    ) -> Self {
+        Self {
+            governor: Governor::new(limits),
+            mode: Mode::default(),
+            is_armed: false,
+            last_activity: None,
+            idle_timeout,
+            last_heartbeat: None,
+            heartbeat_timeout,
+            safeword,
+            lockout: None,
+            is_firing: false,
+            is_test_mode,
+        }
+    }
+
+    // Nothing is accepted while the pump is running. This has the effect that if the user presses
+    // the button to trigger the toy while it's already doing something, it will be a no-op rather
+    // than queue up I2C writes. We want to wait until the toy is done doing its thing before we
+    // allow it to be activated again.
+    //
+    // Every request that's accepted must be followed by a call to `finish`.
+    pub fn request(&mut self, now: Instant) -> Outcome {
+        let outcome = self.check_armed(now);
+
+        if outcome != Outcome::Accepted {
+            return outcome;
+        }
+
+        if self.is_firing {
+            return Outcome::Busy;
+        }
+
+        if let Err(limited) = self.governor.check(now) {
+            return Outcome::RateLimited(limited);
+        }
+
+        self.governor.record_fire(now);
+        self.is_firing = true;
+        self.touch(now);
+
+        if self.is_test_mode {
+            Outcome::TestMode
+        } else {
+            Outcome::Accepted
+        }
+    }
+
+    // Whether the toy is allowed to do anything at all right now.
+    pub fn check_armed(&self, now: Instant) -> Outcome {
+        if let Some(remaining) = self.lockout_remaining(now) {
+            return Outcome::LockedOut(remaining);
+        }
+
+        if !self.is_armed {
+            return Outcome::Disarmed;
+        }
+
+        Outcome::Accepted
+    }
+
+    // The pump thread is done with a request, having run the pump for `on_time` ending at `now`.
+    pub fn finish(&mut self, now: Instant, on_time: Duration) {
+        self.is_firing = false;
+        self.governor.record_run(now, on_time);
+        self.touch(now);
+    }
+
+    // Something happened that means the toy isn't idle, like the user pressing a button.
+    pub fn touch(&mut self, now: Instant) {
+        if self.is_armed {
+            self.last_activity = Some(now);
+        }
+    }
+
+    // How long until the toy disarms itself for being idle, or `None` if it isn't counting down.
+    // The toy isn't idle while it's firing or in auto mode.
+    pub fn idle_remaining(&self, now: Instant) -> Option<Duration> {
+        if self.idle_timeout.is_zero() || self.is_firing || self.mode != Mode::Manual {
+            return None;
+        }
+
+        self.last_activity.map(|last_activity| {
+            self.idle_timeout
+                .saturating_sub(now.saturating_duration_since(last_activity))
+        })
+    }
+
+    // The remote is still there.
+    pub fn heartbeat(&mut self, now: Instant) {
+        self.last_heartbeat = Some(now);
+    }
+
+    // How long until we give up on the remote, or `None` if we aren't waiting on it. We only wait
+    // on it while auto mode is on or the pump is running, and only once it's sent a heartbeat.
+    pub fn heartbeat_remaining(&self, now: Instant) -> Option<Duration> {
+        if self.heartbeat_timeout.is_zero() || (self.mode == Mode::Manual && !self.is_firing) {
+            return None;
+        }
+
+        self.last_heartbeat.map(|last_heartbeat| {
+            self.heartbeat_timeout
+                .saturating_sub(now.saturating_duration_since(last_heartbeat))
+        })
+    }
+
+    // We've given up on the remote, so stop auto mode and stop waiting on it until it comes back.
+    // It's up to the pump thread to actually stop the pump.
+    pub fn lose_heartbeat(&mut self) {
+        self.mode = Mode::Manual;
+        self.last_heartbeat = None;
+    }
+
+    // How much longer the pump is allowed to run this minute, or `None` if there's no limit.
+    pub fn remaining_on_time(&mut self, now: Instant) -> Option<Duration> {
+        self.governor.remaining_on_time(now)
+    }
+
+    // Auto mode can't run while the toy is disarmed.
+    pub fn start_auto(&mut self, now: Instant) -> Outcome {
+        let outcome = self.check_armed(now);
+
+        if outcome == Outcome::Accepted {
+            self.mode = Mode::Auto;
+        }
+
+        outcome
+    }
+
+    pub fn stop_auto(&mut self) {
+        self.mode = Mode::Manual;
+    }
+
+    // Pausing only means something while auto mode is on.
+    pub fn pause_auto(&mut self) {
+        if self.mode == Mode::Auto {
+            self.mode = Mode::Paused;
+        }
+    }
+
+    pub fn resume_auto(&mut self) {
+        if self.mode == Mode::Paused {
+            self.mode = Mode::Auto;
+        }
+    }
+
+    // Stop auto mode and refuse everything until the toy is armed again. It's up to the pump thread
+    // to actually stop the pump.
+    pub fn disarm(&mut self) {
+        self.mode = Mode::Manual;
+        self.is_armed = false;
+        self.last_activity = None;
+    }
+
+    // The toy can't be armed during a safeword lockout.
+    pub fn arm(&mut self, now: Instant) -> Outcome {
+        if let Some(remaining) = self.lockout_remaining(now) {
+            return Outcome::LockedOut(remaining);
+        }
+
+        self.is_armed = true;
+        self.last_activity = Some(now);
+
+        Outcome::Accepted
+    }
+
+    // Disarm the toy, and don't let anyone arm it again until the lockout is over.
+    pub fn safeword(&mut self, now: Instant) {
+        self.disarm();
+
+        self.lockout = Some(Lockout {
+            until: now + self.safeword.lockout,
+            failed_attempts: 0,
+        });
+    }
+
+    // How long until the safeword lockout ends, or `None` if there isn't one.
+    pub fn lockout_remaining(&self, now: Instant) -> Option<Duration> {
+        self.lockout
+            .map(|lockout| lockout.until.saturating_duration_since(now))
+            .filter(|remaining| !remaining.is_zero())
+    }
+
+    // End the lockout early. The toy stays disarmed.
+    pub fn lift_lockout(&mut self, now: Instant, pin: &str) -> anyhow::Result<()> {
+        let Some(lockout) = self.lockout.as_mut().filter(|lockout| lockout.until > now) else {
+            bail!("The toy isn't locked.");
+        };
+
+        let Some(expected) = &self.safeword.pin else {
+            bail!("There's no PIN, so the lockout can't be lifted early.");
+        };
+
+        if lockout.failed_attempts >= Self::MAX_PIN_ATTEMPTS {
+            bail!("Too many wrong PINs. Wait for the lockout to end.");
+        }
+
+        if pin != expected {
+            lockout.failed_attempts += 1;
+            bail!("Wrong PIN.");
+        }
+
+        self.lockout = None;
+
+        Ok(())
+    }
+
+    pub fn snapshot(&mut self, now: Instant) -> Snapshot {
+        let cooldown = self.governor.cooldown(now);
+        let lockout = self.lockout_remaining(now);
+
+        let state = if lockout.is_some() {
+            State::LockedOut
+        } else if !self.is_armed {
+            State::Disarmed
+        } else if self.is_firing {
+            State::Firing
+        } else if cooldown.is_some() {
+            State::Cooldown
+        } else {
+            match self.mode {
+                Mode::Manual => State::Idle,
+                Mode::Auto => State::Auto,
+                Mode::Paused => State::Paused,
+            }
+        };
+
+        Snapshot {
+            state,
+            is_armed: self.is_armed,
+            is_auto: self.mode != Mode::Manual,
+            is_paused: self.mode == Mode::Paused,
+            is_firing: self.is_firing,
+            lockout,
+            cooldown,
+            idle_remaining: self.idle_remaining(now),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::io::{
+        governor::Limit,
+        message::MessageTemplate,
+        pattern::{builtin_patterns, Pattern},
+    };
+
+    fn secs(secs: u64) -> Duration {
+        Duration::from_secs(secs)
+    }
+
+    fn no_limits() -> GovernorLimits {
+        GovernorLimits {
+            max_fires: 0,
+            window: Duration::ZERO,
+            max_on_time: Duration::ZERO,
+            cooldown: Duration::ZERO,
+        }
+    }
+
+    fn safeword() -> SafewordConfig {
+        SafewordConfig {
+            lockout: secs(60),
+            pin: Some(String::from("1234")),
+        }
+    }
+
+    fn machine(limits: GovernorLimits) -> StateMachine {
+        StateMachine::new(limits, Duration::ZERO, Duration::ZERO, safeword(), false)
+    }
+
+    #[test]
+    fn fires_once_armed() {
+        let now = Instant::now();
+        let mut machine = machine(no_limits());
+
+        assert_eq!(machine.snapshot(now).state, State::Disarmed);
+        assert_eq!(machine.request(now), Outcome::Disarmed);
+
+        assert_eq!(machine.arm(now), Outcome::Accepted);
+        assert_eq!(machine.snapshot(now).state, State::Idle);
+
+        assert_eq!(machine.request(now), Outcome::Accepted);
+        assert_eq!(machine.snapshot(now).state, State::Firing);
+        assert!(machine.snapshot(now).is_firing);
+
+        machine.finish(now + secs(1), secs(1));
+
+        assert_eq!(machine.snapshot(now + secs(1)).state, State::Idle);
+        assert!(!machine.snapshot(now + secs(1)).is_firing);
+    }
+
+    #[test]
+    fn is_busy_while_firing() {
+        let now = Instant::now();
+        let mut machine = machine(no_limits());
+
+        machine.arm(now);
+
+        assert_eq!(machine.request(now), Outcome::Accepted);
+        assert_eq!(machine.request(now), Outcome::Busy);
+
+        machine.finish(now, Duration::ZERO);
+
+        assert_eq!(machine.request(now), Outcome::Accepted);
+    }
+
+    #[test]
+    fn default_fires_count_against_the_on_time_limit() {
+        let start = Instant::now();
+        let block_time = secs(2);
+        let mut machine = machine(GovernorLimits {
+            max_on_time: secs(5),
+            ..no_limits()
+        });
+
+        // With the default config, the pump controller decides how long each squirt lasts.
+        let timeline = builtin_patterns()
+            .into_iter()
+            .find(|pattern| pattern.name == Pattern::DEFAULT_NAME)
+            .unwrap()
+            .timeline(&MessageTemplate::default(), 255, Duration::ZERO);
+
+        machine.arm(start);
+
+        let mut now = start;
+
+        for _ in 0..3 {
+            assert_eq!(machine.request(now), Outcome::Accepted);
+            now += block_time;
+            machine.finish(now, timeline.charged_time(block_time));
+        }
+
+        assert!(matches!(
+            machine.request(now),
+            Outcome::RateLimited(RateLimited {
+                limit: Limit::OnTime,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn reports_test_mode() {
+        let now = Instant::now();
+        let mut machine = StateMachine::new(
+            no_limits(),
+            Duration::ZERO,
+            Duration::ZERO,
+            safeword(),
+            true,
+        );
+
+        machine.arm(now);
+
+        assert_eq!(machine.request(now), Outcome::TestMode);
+        assert_eq!(machine.request(now), Outcome::Busy);
+    }
+
+    #[test]
+    fn pauses_and_resumes_auto_mode() {
+        let now = Instant::now();
+        let mut machine = machine(no_limits());
+
+        // Pausing means nothing outside of auto mode.
+        machine.arm(now);
+        machine.pause_auto();
+
+        assert_eq!(machine.snapshot(now).state, State::Idle);
+
+        assert_eq!(machine.start_auto(now), Outcome::Accepted);
+        assert_eq!(machine.snapshot(now).state, State::Auto);
+
+        machine.pause_auto();
+
+        let snapshot = machine.snapshot(now);
+        assert_eq!(snapshot.state, State::Paused);
+        assert!(snapshot.is_auto);
+        assert!(snapshot.is_paused);
+
+        machine.resume_auto();
+
+        let snapshot = machine.snapshot(now);
+        assert_eq!(snapshot.state, State::Auto);
+        assert!(!snapshot.is_paused);
+
+        machine.pause_auto();
+        machine.stop_auto();
+        machine.resume_auto();
+
+        assert_eq!(machine.snapshot(now).state, State::Idle);
+    }
+
+    #[test]
+    fn auto_mode_needs_the_toy_armed() {
+        let now = Instant::now();
+        let mut machine = machine(no_limits());
+
+        assert_eq!(machine.start_auto(now), Outcome::Disarmed);
+
+        machine.arm(now);
+        machine.start_auto(now);
+        machine.disarm();
+
+        let snapshot = machine.snapshot(now);
+        assert_eq!(snapshot.state, State::Disarmed);
+        assert!(!snapshot.is_auto);
+    }
+
+    #[test]
+    fn safeword_locks_out_until_the_right_pin() {
+        let now = Instant::now();
+        let mut machine = machine(no_limits());
+
+        machine.arm(now);
+        machine.safeword(now);
+
+        assert_eq!(machine.snapshot(now).state, State::LockedOut);
+        assert_eq!(machine.request(now), Outcome::LockedOut(secs(60)));
+        assert_eq!(machine.arm(now + secs(10)), Outcome::LockedOut(secs(50)));
+
+        assert!(machine.lift_lockout(now, "0000").is_err());
+        assert!(machine.lift_lockout(now, "1234").is_ok());
+
+        // Lifting the lockout doesn't arm the toy.
+        assert_eq!(machine.snapshot(now).state, State::Disarmed);
+        assert_eq!(machine.arm(now), Outcome::Accepted);
+    }
+
+    #[test]
+    fn safeword_pin_stops_working_after_too_many_attempts() {
+        let now = Instant::now();
+        let mut machine = machine(no_limits());
+
+        machine.safeword(now);
+
+        for _ in 0..StateMachine::MAX_PIN_ATTEMPTS {
+            assert!(machine.lift_lockout(now, "0000").is_err());
+        }
+
+        assert!(machine.lift_lockout(now, "1234").is_err());
+        assert_eq!(machine.lockout_remaining(now), Some(secs(60)));
+
+        // Once the lockout runs out on its own, the toy can be armed again.
+        assert_eq!(machine.lockout_remaining(now + secs(60)), None);
+        assert!(machine.lift_lockout(now + secs(60), "1234").is_err());
+        assert_eq!(machine.arm(now + secs(60)), Outcome::Accepted);
+    }
+
+    #[test]
+    fn safeword_without_a_pin_cant_be_lifted_early() {
+        let now = Instant::now();
+        let mut machine = StateMachine::new(
+            no_limits(),
+            Duration::ZERO,
+            Duration::ZERO,
+            SafewordConfig {
+                lockout: secs(60),
+                pin: None,
+            },
+            false,
+        );
+
+        machine.safeword(now);
+
+        assert!(machine.lift_lockout(now, "").is_err());
+        assert_eq!(machine.snapshot(now).state, State::LockedOut);
+    }
+
+    #[test]
+    fn snapshot_state_follows_precedence() {
+        let now = Instant::now();
+        let mut machine = machine(GovernorLimits {
+            max_fires: 1,
+            window: secs(60),
+            cooldown: secs(10),
+            ..no_limits()
+        });
+
+        machine.arm(now);
+        machine.start_auto(now);
+
+        assert_eq!(machine.snapshot(now).state, State::Auto);
+
+        // Firing wins over auto mode.
+        machine.request(now);
+
+        assert_eq!(machine.snapshot(now).state, State::Firing);
+
+        machine.finish(now, Duration::ZERO);
+
+        // Cooling down wins over auto mode.
+        assert!(matches!(machine.request(now), Outcome::RateLimited(_)));
+
+        let snapshot = machine.snapshot(now);
+        assert_eq!(snapshot.state, State::Cooldown);
+        assert_eq!(snapshot.cooldown, Some(secs(10)));
+
+        // Being disarmed wins over cooling down.
+        machine.disarm();
+
+        assert_eq!(machine.snapshot(now).state, State::Disarmed);
+
+        // Being locked out wins over everything.
+        machine.safeword(now);
+
+        assert_eq!(machine.snapshot(now).state, State::LockedOut);
+    }
+
+    #[test]
+    fn locked_out_wins_over_firing() {
+        let now = Instant::now();
+        let mut machine = machine(no_limits());
+
+        machine.arm(now);
+        machine.request(now);
+        machine.safeword(now);
+
+        let snapshot = machine.snapshot(now);
+        assert_eq!(snapshot.state, State::LockedOut);
+        assert!(snapshot.is_firing);
+    }
+
+    #[test]
+    fn heartbeats_dont_postpone_the_idle_disarm() {
+        let start = Instant::now();
+        let mut machine = StateMachine::new(no_limits(), secs(60), secs(10), safeword(), false);
+
+        machine.arm(start);
+
+        // An open page keeps sending these even if nobody is using it.
+        for offset in (5..=60).step_by(5) {
+            machine.heartbeat(start + secs(offset));
+        }
+
+        assert_eq!(
+            machine.idle_remaining(start + secs(60)),
+            Some(Duration::ZERO)
+        );
+
+        // Actually using the toy does.
+        machine.touch(start + secs(60));
+
+        assert_eq!(machine.idle_remaining(start + secs(60)), Some(secs(60)));
+    }
+}
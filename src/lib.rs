@@ -0,0 +1,16 @@
+// The parts of the firmware that don't depend on ESP-IDF live here, so that their tests can run on
+// the host. The binary re-exports them from its own modules, so the rest of the firmware doesn't
+// need to know the difference.
+pub mod io {
+    pub mod distribution;
+    pub mod driver;
+    pub mod governor;
+    pub mod message;
+    pub mod pattern;
+    pub mod scheduler;
+    pub mod state;
+}
+
+pub mod wifi {
+    pub mod strategy;
+}
@@ -1,15 +1,13 @@
 mod config;
 mod http;
 mod io;
-mod queue;
 mod wifi;
 
-use std::{future::Future, pin::Pin, sync::Arc};
+use std::sync::Arc;
 
 use esp_idf_svc::{
     eventloop::EspSystemEventLoop,
     hal::{self, prelude::Peripherals, task::block_on},
-    mdns::EspMdns,
     nvs::EspDefaultNvsPartition,
     timer::EspTaskTimerService,
 };
@@ -26,36 +24,43 @@ fn run() -> anyhow::Result<Never> {
     let timer_service = EspTaskTimerService::new()?;
     let nvs_part = EspDefaultNvsPartition::take()?;
 
-    let mut wifi = block_on(wifi::init(
+    let wifi = block_on(wifi::init(
         peripherals.modem,
         nvs_part.clone(),
         sysloop.clone(),
-        timer_service.clone(),
+        timer_service,
     ))?;
 
-    // Don't block waiting for the connection to be established just yet. We want to bring up the
-    // HTTP server in the meantime so that users can potentially connect to the device in AP mode
-    // while waiting for it to connect to the local network in STA mode (or in case it's unable
-    // to).
-    let connection: Pin<Box<dyn Future<Output = _>>> =
-        if config::wifi_is_configured(nvs_part.clone())? {
-            Box::pin(wifi::connect(&mut wifi, nvs_part.clone(), timer_service))
-        } else {
-            Box::pin(std::future::ready(Ok(())))
-        };
+    let (wifi_handle, wifi_commands) = wifi::WifiHandle::new();
 
-    let signaler = Arc::new(io::Signaler::new());
+    let signaler = Arc::new(io::Signaler::new(
+        config::governor_limits()?,
+        config::arming_idle_timeout()?,
+        config::heartbeat_timeout()?,
+        config::safeword()?,
+        config::io_driver()? == config::IoDriver::Null,
+    ));
 
     // Don't drop this.
-    let _server = http::serve(nvs_part.clone(), Arc::clone(&signaler))?;
-
-    block_on(connection)?;
-
-    let mut mdns = EspMdns::take()?;
-    wifi::configure_mdns(&mut mdns, &config::wifi_hostname()?)?;
+    let _stations = wifi::track_stations(
+        &sysloop,
+        Arc::clone(&signaler),
+        Arc::clone(wifi_handle.status()),
+    )?;
 
     // Don't drop this.
-    let _subscription = wifi::handle_events(&sysloop)?;
+    let _server = http::serve(nvs_part.clone(), Arc::clone(&signaler), wifi_handle.clone())?;
+
+    // Don't wait for the connection to the local network. Users can connect to the device in AP
+    // mode and control it while it's connecting in STA mode (or in case it's unable to).
+    wifi::keep_connected(
+        wifi,
+        nvs_part.clone(),
+        sysloop,
+        Arc::clone(&signaler),
+        wifi_handle,
+        wifi_commands,
+    )?;
 
     io::listen(nvs_part, peripherals.i2c0, peripherals.pins, signaler)
 }
@@ -1,84 +1,497 @@
-use std::{cmp, time::Duration};
+mod event;
 
-use anyhow::anyhow;
+use std::{
+    cmp,
+    collections::VecDeque,
+    fmt,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        mpsc, Arc, Mutex, MutexGuard, PoisonError,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail};
 use esp_idf_svc::{
     eventloop::{self, EspSubscription, EspSystemEventLoop},
-    hal::{self, modem::Modem, peripheral::Peripheral},
+    hal::{self, modem::Modem, peripheral::Peripheral, task::block_on},
     mdns::EspMdns,
     netif::EspNetif,
     nvs::{EspDefaultNvsPartition, EspNvsPartition, NvsPartitionId},
-    sys::ESP_ERR_TIMEOUT,
+    sys::{
+        wifi_err_reason_t_WIFI_REASON_4WAY_HANDSHAKE_TIMEOUT,
+        wifi_err_reason_t_WIFI_REASON_802_1X_AUTH_FAILED,
+        wifi_err_reason_t_WIFI_REASON_ASSOC_EXPIRE, wifi_err_reason_t_WIFI_REASON_ASSOC_LEAVE,
+        wifi_err_reason_t_WIFI_REASON_AUTH_EXPIRE, wifi_err_reason_t_WIFI_REASON_AUTH_FAIL,
+        wifi_err_reason_t_WIFI_REASON_BEACON_TIMEOUT,
+        wifi_err_reason_t_WIFI_REASON_HANDSHAKE_TIMEOUT, wifi_err_reason_t_WIFI_REASON_MIC_FAILURE,
+        wifi_err_reason_t_WIFI_REASON_NO_AP_FOUND, ESP_ERR_TIMEOUT,
+    },
     timer::EspTaskTimerService,
-    wifi::{AsyncWifi, EspWifi, WifiDriver, WifiEvent},
+    wifi::{
+        AsyncWifi, AuthMethod, ClientConfiguration, Configuration, EspWifi, WifiDriver, WifiEvent,
+    },
 };
 
-use crate::config;
+use squirtinator::wifi::strategy::{ConnectAttempts, ConnectStrategy};
 
-// In my testing, it can sometimes take the device a few attempts to connect to the local network,
-// even with a strong signal.
-//
-// While it's prudent to use exponential backoff, we also want to get the device connected ASAP,
-// because sex toys are a particular class of device that users have very little patience for
-// debugging (and why should they). So we make *n* "eager" attempts first, before we start applying
-// backoff.
-//
-// We also cap the maximum backoff; a mobile device such as this may be on an unstable network or
-// move in and out of range of networks, so we don't want to give up entirely.
-//
-// We need to be somewhat aggressive about connection attempts because users won't have the benefit
-// of log messages or helpful code comments to explain why they can't get their sex toy to work.
-#[derive(Debug, Clone, Copy)]
-enum ConnectStrategy {
-    Eager { attempt: u32 },
-    Backoff { time: Duration },
+use crate::{config, io, Never};
+
+use event::StaDisconnected;
+
+const WIFI_STACK_SIZE: usize = 8192;
+
+// How often to check the signal strength while we're connected, and how many of those samples to
+// keep around.
+const RSSI_SAMPLE_INTERVAL: Duration = Duration::from_secs(10);
+const MAX_RSSI_SAMPLES: usize = 30;
+
+// How many connection attempts to remember.
+const MAX_ATTEMPTS: usize = 10;
+
+// Why we couldn't connect to the local network.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Failure {
+    AuthFailed,
+    NotFound,
+    DhcpFailed,
+    Timeout,
+    // Anything else, like the network turning us away or the driver itself failing.
+    Other,
 }
 
-impl Default for ConnectStrategy {
-    fn default() -> Self {
-        Self::Eager { attempt: 0 }
+impl Failure {
+    // How this is stored in NVS.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::AuthFailed => "auth_failed",
+            Self::NotFound => "not_found",
+            Self::DhcpFailed => "dhcp_failed",
+            Self::Timeout => "timeout",
+            Self::Other => "other",
+        }
     }
-}
 
-impl ConnectStrategy {
-    pub const EAGER_ATTEMPTS: u32 = 3;
-    pub const MAX_BACKOFF: Duration = Duration::from_secs(2u64.pow(4));
-    pub const BACKOFF_MULTIPLIER: u32 = 2;
+    pub fn from_code(code: &str) -> Option<Self> {
+        match code {
+            "auth_failed" => Some(Self::AuthFailed),
+            "not_found" => Some(Self::NotFound),
+            "dhcp_failed" => Some(Self::DhcpFailed),
+            "timeout" => Some(Self::Timeout),
+            "other" => Some(Self::Other),
+            _ => None,
+        }
+    }
+
+    // What the reason the driver gave for disconnecting us means for the user.
+    #[allow(non_upper_case_globals)]
+    fn from_reason(reason: u16) -> Self {
+        match u32::from(reason) {
+            wifi_err_reason_t_WIFI_REASON_NO_AP_FOUND => Self::NotFound,
+            // A wrong password usually shows up as the handshake failing or timing out, rather
+            // than as an explicit authentication failure.
+            wifi_err_reason_t_WIFI_REASON_AUTH_FAIL
+            | wifi_err_reason_t_WIFI_REASON_AUTH_EXPIRE
+            | wifi_err_reason_t_WIFI_REASON_4WAY_HANDSHAKE_TIMEOUT
+            | wifi_err_reason_t_WIFI_REASON_HANDSHAKE_TIMEOUT
+            | wifi_err_reason_t_WIFI_REASON_MIC_FAILURE
+            | wifi_err_reason_t_WIFI_REASON_802_1X_AUTH_FAILED => Self::AuthFailed,
+            wifi_err_reason_t_WIFI_REASON_BEACON_TIMEOUT
+            | wifi_err_reason_t_WIFI_REASON_ASSOC_EXPIRE => Self::Timeout,
+            _ => Self::Other,
+        }
+    }
+}
 
-    pub fn next_attempt(&mut self) {
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Self::Eager { attempt: attempts } if *attempts >= Self::EAGER_ATTEMPTS => {
-                *self = Self::Backoff {
-                    time: Duration::from_secs(1),
-                };
-            }
-            Self::Eager { attempt: attempts } => {
-                *attempts += 1;
-            }
-            Self::Backoff { time } => {
-                *time = cmp::min(*time * Self::BACKOFF_MULTIPLIER, Self::MAX_BACKOFF);
-            }
+            Self::AuthFailed => write!(
+                f,
+                "The network was found, but the toy couldn't join it. Check the password."
+            ),
+            Self::NotFound => write!(
+                f,
+                "The network couldn't be found. Check the name, and make sure the toy is in range."
+            ),
+            Self::DhcpFailed => write!(
+                f,
+                "The toy joined the network, but it didn't get an IP address."
+            ),
+            Self::Timeout => write!(
+                f,
+                "The network stopped responding. Make sure the toy is in range, and try again."
+            ),
+            Self::Other => write!(
+                f,
+                "Something went wrong connecting to the network. Try again."
+            ),
+        }
+    }
+}
+
+// Where we are in connecting to the local network in STA mode.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Connection {
+    // Either we haven't tried yet, or there's no network configured.
+    #[default]
+    Disconnected,
+    Connecting {
+        attempt: u32,
+    },
+    // Waiting `delay` before making the given attempt, which will happen at `until`.
+    BackingOff {
+        attempt: u32,
+        delay: Duration,
+        until: Instant,
+    },
+    Connected,
+    // We gave up and fell back to AP mode only.
+    Failed(Failure),
+}
+
+// A past attempt to connect to the local network, and why it failed, if it did.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Attempt {
+    pub at: Instant,
+    pub failure: Option<Failure>,
+}
+
+// The access point we're connected to on the local network.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Link {
+    pub bssid: [u8; 6],
+    pub channel: u8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RssiSample {
+    pub at: Instant,
+    // In dBm.
+    pub rssi: i8,
+}
+
+// A network that showed up in a scan.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Network {
+    pub ssid: String,
+    // In dBm. When more than one access point has the same SSID, this is the strongest.
+    pub rssi: i8,
+    pub auth_method: Option<AuthMethod>,
+}
+
+impl Network {
+    pub fn auth(&self) -> &'static str {
+        match self.auth_method {
+            None | Some(AuthMethod::None) => "open",
+            Some(AuthMethod::WEP) => "wep",
+            Some(AuthMethod::WPA) => "wpa",
+            Some(AuthMethod::WPA2Personal) => "wpa2",
+            Some(AuthMethod::WPAWPA2Personal) => "wpa/wpa2",
+            Some(AuthMethod::WPA2Enterprise) => "wpa2-enterprise",
+            Some(AuthMethod::WPA3Personal) => "wpa3",
+            Some(AuthMethod::WPA2WPA3Personal) => "wpa2/wpa3",
+            Some(AuthMethod::WAPIPersonal) => "wapi",
         }
     }
 }
 
-pub async fn connect<P: NvsPartitionId>(
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum Scan {
+    #[default]
+    Idle,
+    // Someone asked for a scan, and it'll happen as soon as the WiFi is free.
+    Pending,
+    Done {
+        at: Instant,
+        // The strongest come first.
+        networks: Vec<Network>,
+    },
+    Failed,
+}
+
+// Where we are in testing new credentials for the local network before they're saved.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CredentialTest {
+    #[default]
+    Idle,
+    // Someone asked for a test, and it'll happen as soon as the WiFi is free.
+    Pending,
+    // The toy connected, so the credentials were saved.
+    Passed,
+    // The toy couldn't connect, so the credentials weren't saved.
+    Failed(Failure),
+    // Something went wrong that had nothing to do with the credentials.
+    Error,
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+// What the WiFi is up to, as far as the rest of the device is concerned.
+#[derive(Debug, Default)]
+pub struct WifiStatus {
+    // How many devices are connected to the toy's access point.
+    stations: AtomicU32,
+    connection: Mutex<Connection>,
+    // The last time the saved settings changed, we couldn't switch to them, so we're still using
+    // the ones from before.
+    settings_failed: AtomicBool,
+    // The most recent attempts come last.
+    attempts: Mutex<VecDeque<Attempt>>,
+    link: Mutex<Option<Link>>,
+    // The most recent samples come last. These are only taken while we're connected.
+    rssi_samples: Mutex<VecDeque<RssiSample>>,
+    scan: Mutex<Scan>,
+    credential_test: Mutex<CredentialTest>,
+    // Why the driver last disconnected us, as one of its `WIFI_REASON_*` codes.
+    disconnect_reason: Mutex<Option<u16>>,
+}
+
+impl WifiStatus {
+    pub fn stations(&self) -> u32 {
+        self.stations.load(Ordering::Relaxed)
+    }
+
+    pub fn connection(&self) -> Connection {
+        *lock(&self.connection)
+    }
+
+    fn set_connection(&self, connection: Connection) {
+        *lock(&self.connection) = connection;
+    }
+
+    pub fn settings_failed(&self) -> bool {
+        self.settings_failed.load(Ordering::Relaxed)
+    }
+
+    fn set_settings_failed(&self, failed: bool) {
+        self.settings_failed.store(failed, Ordering::Relaxed);
+    }
+
+    pub fn attempts(&self) -> Vec<Attempt> {
+        lock(&self.attempts).iter().copied().collect()
+    }
+
+    fn record_attempt(&self, failure: Option<Failure>) {
+        let mut attempts = lock(&self.attempts);
+
+        if attempts.len() >= MAX_ATTEMPTS {
+            attempts.pop_front();
+        }
+
+        attempts.push_back(Attempt {
+            at: Instant::now(),
+            failure,
+        });
+    }
+
+    // Why the last attempt to connect failed, if it did.
+    pub fn last_failure(&self) -> Option<Failure> {
+        lock(&self.attempts)
+            .back()
+            .and_then(|attempt| attempt.failure)
+    }
+
+    pub fn link(&self) -> Option<Link> {
+        *lock(&self.link)
+    }
+
+    fn set_link(&self, link: Option<Link>) {
+        *lock(&self.link) = link;
+    }
+
+    pub fn rssi_samples(&self) -> Vec<RssiSample> {
+        lock(&self.rssi_samples).iter().copied().collect()
+    }
+
+    fn record_rssi(&self, rssi: i8) {
+        let mut samples = lock(&self.rssi_samples);
+
+        if samples.len() >= MAX_RSSI_SAMPLES {
+            samples.pop_front();
+        }
+
+        samples.push_back(RssiSample {
+            at: Instant::now(),
+            rssi,
+        });
+    }
+
+    pub fn scan(&self) -> Scan {
+        lock(&self.scan).clone()
+    }
+
+    fn set_scan(&self, scan: Scan) {
+        *lock(&self.scan) = scan;
+    }
+
+    pub fn credential_test(&self) -> CredentialTest {
+        *lock(&self.credential_test)
+    }
+
+    fn set_credential_test(&self, test: CredentialTest) {
+        *lock(&self.credential_test) = test;
+    }
+
+    fn set_disconnect_reason(&self, reason: u16) {
+        *lock(&self.disconnect_reason) = Some(reason);
+    }
+
+    fn take_disconnect_reason(&self) -> Option<u16> {
+        lock(&self.disconnect_reason).take()
+    }
+
+    pub fn is_sta_connected(&self) -> bool {
+        self.connection() == Connection::Connected
+    }
+}
+
+// What the WiFi thread can be asked to do.
+#[derive(Debug)]
+pub enum Command {
+    // We lost the connection to the local network.
+    Reconnect,
+    Scan,
+    // Try to connect to a network, and save it if that works.
+    TestCredentials { ssid: String, password: String },
+    // The saved settings changed, so switch to them.
+    ApplySettings,
+}
+
+// The rest of the device doesn't get to touch the WiFi directly, because the WiFi thread owns it.
+// Instead, it can see what the WiFi is up to and ask the WiFi thread to do things.
+#[derive(Debug, Clone)]
+pub struct WifiHandle {
+    status: Arc<WifiStatus>,
+    commands: mpsc::Sender<Command>,
+}
+
+impl WifiHandle {
+    pub fn new() -> (Self, mpsc::Receiver<Command>) {
+        let (commands, receiver) = mpsc::channel();
+
+        let handle = Self {
+            status: Arc::new(WifiStatus::default()),
+            commands,
+        };
+
+        (handle, receiver)
+    }
+
+    pub fn status(&self) -> &Arc<WifiStatus> {
+        &self.status
+    }
+
+    // Scan for nearby networks. The results show up in the status once it's done.
+    pub fn scan(&self) -> anyhow::Result<()> {
+        // If there's already a scan on the way, there's no need for another.
+        if self.status.scan() == Scan::Pending {
+            return Ok(());
+        }
+
+        self.status.set_scan(Scan::Pending);
+
+        self.commands
+            .send(Command::Scan)
+            .map_err(|_| anyhow!("The WiFi thread is not running."))
+    }
+
+    // Switch to the saved WiFi settings without restarting.
+    pub fn apply_settings(&self) -> anyhow::Result<()> {
+        self.commands
+            .send(Command::ApplySettings)
+            .map_err(|_| anyhow!("The WiFi thread is not running."))
+    }
+
+    // Test credentials for the local network, and save them if they work. The result shows up in
+    // the status once it's done. An empty password means the network is open.
+    pub fn test_credentials(&self, ssid: &str, password: &str) -> anyhow::Result<()> {
+        if self.status.credential_test() == CredentialTest::Pending {
+            bail!("The toy is already testing WiFi settings.");
+        }
+
+        self.status.set_credential_test(CredentialTest::Pending);
+
+        self.commands
+            .send(Command::TestCredentials {
+                ssid: ssid.to_string(),
+                password: password.to_string(),
+            })
+            .map_err(|_| anyhow!("The WiFi thread is not running."))
+    }
+}
+
+// Make a single attempt to connect to whichever network the driver is configured for, and work out
+// why it failed if it did. The driver tells us why it disconnected us through an event, which
+// `track_disconnects` keeps for us.
+async fn try_connect(
     wifi: &mut AsyncWifi<EspWifi<'static>>,
-    nvs_part: EspNvsPartition<P>,
-    timer_service: EspTaskTimerService,
-) -> anyhow::Result<()> {
-    let mut strategy = ConnectStrategy::default();
-    let mut timer = timer_service.timer_async()?;
+    status: &WifiStatus,
+) -> Option<Failure> {
+    // Anything left over is from some earlier attempt.
+    status.take_disconnect_reason();
 
-    loop {
-        strategy.next_attempt();
+    if let Err(err) = wifi.connect().await {
+        let reason = status.take_disconnect_reason();
+
+        log::warn!("Could not connect to WiFi (reason {:?}): {:?}", reason, err);
+
+        // Make sure the driver isn't still trying in the background when we make the next
+        // attempt.
+        if let Err(err) = wifi.disconnect().await {
+            log::warn!("Could not disconnect from WiFi: {:?}", err);
+        }
+
+        return Some(match reason {
+            Some(reason) => Failure::from_reason(reason),
+            None if err.code() == ESP_ERR_TIMEOUT => Failure::Timeout,
+            None => Failure::Other,
+        });
+    }
+
+    log::info!("WiFi connected.");
+
+    if let Err(err) = wifi.wait_netif_up().await {
+        log::warn!("Could not get an IP address: {:?}", err);
+
+        if let Err(err) = wifi.disconnect().await {
+            log::warn!("Could not disconnect from WiFi: {:?}", err);
+        }
+
+        return Some(Failure::DhcpFailed);
+    }
+
+    log::info!("WiFi netif up.");
+
+    None
+}
+
+// We're in the middle of connecting to the local network. Each attempt happens when it's due, and
+// the WiFi thread does whatever else it's asked in between. Every kind of failure counts toward
+// `max_failures`, and none of them are worth restarting the device over.
+#[derive(Debug)]
+struct Connecting {
+    attempts: ConnectAttempts,
+    attempt: u32,
+    // When the next attempt is due.
+    at: Instant,
+}
+
+impl Connecting {
+    fn start(max_failures: u32, status: &WifiStatus) -> Self {
+        Self::schedule(ConnectAttempts::new(max_failures), status)
+    }
+
+    fn schedule(mut attempts: ConnectAttempts, status: &WifiStatus) -> Self {
+        let (attempt, strategy) = attempts.next_attempt();
+        let at = Instant::now() + strategy.delay();
 
         match strategy {
-            ConnectStrategy::Eager { attempt } => {
-                log::info!(
-                    "Connecting to WiFI in STA mode (attempt {} of {})...",
-                    attempt,
-                    ConnectStrategy::EAGER_ATTEMPTS
-                );
+            ConnectStrategy::Eager { .. } => {
+                status.set_connection(Connection::Connecting { attempt });
             }
             ConnectStrategy::Backoff { time } => {
                 log::info!(
@@ -86,30 +499,229 @@ pub async fn connect<P: NvsPartitionId>(
                     time.as_secs()
                 );
 
-                timer.after(time).await?;
-
-                log::info!("Connecting to WiFI in STA mode...");
+                status.set_connection(Connection::BackingOff {
+                    attempt,
+                    delay: time,
+                    until: at,
+                });
             }
         }
 
-        match wifi.connect().await {
-            Err(err) if err.code() == ESP_ERR_TIMEOUT => {
-                log::warn!("WiFi connection attempt timed out. Retrying...",);
-                continue;
-            }
-            Err(err) => return Err(err.into()),
-            Ok(_) => {
-                log::info!("WiFi connected.");
+        Self {
+            attempts,
+            attempt,
+            at,
+        }
+    }
 
-                wifi.wait_netif_up().await?;
-                log::info!("WiFi netif up.");
+    // How long until the next attempt is due.
+    fn timeout(&self) -> Duration {
+        self.at.saturating_duration_since(Instant::now())
+    }
 
-                let addr = wifi.wifi().sta_netif().get_ip_info()?.ip;
-                config::set_wifi_ip_addr(nvs_part, Some(addr))?;
+    // Make the attempt that's due, and schedule the next one if it fails and we haven't given up.
+    fn attempt<P: NvsPartitionId>(
+        self,
+        wifi: &mut AsyncWifi<EspWifi<'static>>,
+        nvs_part: EspNvsPartition<P>,
+        status: &WifiStatus,
+    ) -> Option<Self> {
+        status.set_connection(Connection::Connecting {
+            attempt: self.attempt,
+        });
 
-                return Ok(());
+        log::info!(
+            "Connecting to WiFI in STA mode (attempt {})...",
+            self.attempt
+        );
+
+        let Some(failure) = block_on(try_connect(wifi, status)) else {
+            match wifi.wifi().sta_netif().get_ip_info() {
+                Ok(info) => {
+                    if let Err(err) = config::set_wifi_ip_addr(nvs_part.clone(), Some(info.ip)) {
+                        log::warn!("Could not save the IP address: {:?}", err);
+                    }
+                }
+                Err(err) => log::warn!("Could not get the IP address: {:?}", err),
             }
+
+            if let Err(err) = config::set_wifi_failure(nvs_part, None) {
+                log::warn!("Could not clear the last WiFi failure: {:?}", err);
+            }
+
+            sample_link(wifi, status);
+            status.record_attempt(None);
+            status.set_connection(Connection::Connected);
+
+            return None;
+        };
+
+        log::warn!("Could not connect to WiFi: {:?}", failure);
+
+        status.record_attempt(Some(failure));
+
+        // We keep this around so the user can find out what went wrong, even after a restart.
+        if let Err(err) = config::set_wifi_failure(nvs_part, Some(failure.code())) {
+            log::warn!("Could not save the WiFi failure: {:?}", err);
         }
+
+        if self.attempts.is_exhausted() {
+            log::error!(
+                "Could not connect to WiFi after {} attempts. Falling back to AP mode only.",
+                self.attempt
+            );
+
+            fall_back_to_access_point(wifi);
+
+            status.set_connection(Connection::Failed(failure));
+
+            return None;
+        }
+
+        Some(Self::schedule(self.attempts, status))
+    }
+}
+
+// Stop trying to connect to the local network, and only use the toy's own access point.
+fn fall_back_to_access_point(wifi: &mut AsyncWifi<EspWifi<'static>>) {
+    let result = config::access_point_config().and_then(|ap_config| {
+        wifi.set_configuration(&Configuration::AccessPoint(ap_config))
+            .map_err(Into::into)
+    });
+
+    // The access point is already up either way, and we've stopped trying to connect, so there's
+    // nothing more to do about it.
+    if let Err(err) = result {
+        log::error!("Could not switch to AP mode only: {:?}", err);
+    }
+}
+
+// Try connecting to a network without touching the saved settings, and then put the driver back how
+// it was. The access point stays up the whole time so the user can find out how it went, although
+// devices on it may drop briefly if the access point has to change channels to match the network.
+//
+// If we were connected to the local network, we have to leave it to test the new one. That looks
+// like any other lost connection, so we get disarmed and reconnect afterward, unless the new one
+// works and we switch to it instead.
+async fn test_credentials(
+    wifi: &mut AsyncWifi<EspWifi<'static>>,
+    status: &WifiStatus,
+    ssid: &str,
+    password: &str,
+) -> anyhow::Result<Option<Failure>> {
+    let previous = wifi.get_configuration()?;
+
+    if wifi.is_connected()? {
+        wifi.disconnect().await?;
+    }
+
+    wifi.set_configuration(&Configuration::Mixed(
+        config::client_config(ssid, password)?,
+        config::access_point_config()?,
+    ))?;
+
+    let result = match try_connect(wifi, status).await {
+        // We don't want to stay on a network the rest of the device doesn't know about yet.
+        None => wifi.disconnect().await.map(|()| None).map_err(Into::into),
+        failure => Ok(failure),
+    };
+
+    wifi.set_configuration(&previous)?;
+
+    result
+}
+
+// Reconfigure the driver with whatever the saved settings say, and return whether there's a local
+// network to connect to. The access point stays up the whole time.
+async fn apply_settings<P: NvsPartitionId>(
+    wifi: &mut AsyncWifi<EspWifi<'static>>,
+    nvs_part: EspNvsPartition<P>,
+    status: &WifiStatus,
+    signaler: &io::Signaler,
+) -> anyhow::Result<bool> {
+    // If we can't read the new settings, we haven't touched anything yet.
+    let configuration = config::wifi_config(nvs_part.clone())?;
+
+    // We're leaving the network on purpose, so there's no need to reconnect to it. Anyone using
+    // it can't control the toy anymore though, so it still has to be disarmed.
+    if status.is_sta_connected() {
+        status.set_connection(Connection::Disconnected);
+        status.set_link(None);
+        signaler.send(io::Signal::Disarm);
+    }
+
+    if wifi.is_connected()? {
+        wifi.disconnect().await?;
+    }
+
+    config::set_wifi_ip_addr(nvs_part, None)?;
+    wifi.set_configuration(&configuration)?;
+
+    if let Configuration::Mixed(..) = configuration {
+        return Ok(true);
+    }
+
+    log::info!("No WiFi network configured. Only using AP mode.");
+    status.set_connection(Connection::Disconnected);
+
+    Ok(false)
+}
+
+// Switch to the saved settings, and start connecting to the local network if there is one. If that
+// doesn't work, we go back to the settings we had before, rather than take the WiFi thread down
+// with it, and the user gets told to try again.
+fn switch_settings<P: NvsPartitionId>(
+    wifi: &mut AsyncWifi<EspWifi<'static>>,
+    nvs_part: EspNvsPartition<P>,
+    max_failures: u32,
+    status: &WifiStatus,
+    signaler: &io::Signaler,
+    connecting: Option<Connecting>,
+) -> Option<Connecting> {
+    let previous = wifi.get_configuration();
+
+    let err = match block_on(apply_settings(wifi, nvs_part, status, signaler)) {
+        Ok(should_connect) => {
+            status.set_settings_failed(false);
+
+            // Whatever we were connecting to before, we're done with it.
+            return should_connect.then(|| Connecting::start(max_failures, status));
+        }
+        Err(err) => err,
+    };
+
+    log::error!("Could not apply the new WiFi settings: {:?}", err);
+
+    status.set_settings_failed(true);
+
+    let Ok(previous) = previous else {
+        return connecting;
+    };
+
+    if let Err(err) = wifi.set_configuration(&previous) {
+        log::error!("Could not restore the previous WiFi settings: {:?}", err);
+    }
+
+    // If we didn't get as far as leaving the network, or we were already on our way to it, we can
+    // carry on as we were.
+    if status.is_sta_connected() || connecting.is_some() {
+        return connecting;
+    }
+
+    matches!(previous, Configuration::Mixed(..)).then(|| Connecting::start(max_failures, status))
+}
+
+// Take note of which access point we're connected to and how strong its signal is.
+fn sample_link(wifi: &mut AsyncWifi<EspWifi<'static>>, status: &WifiStatus) {
+    match wifi.wifi_mut().driver_mut().get_ap_info() {
+        Ok(info) => {
+            status.set_link(Some(Link {
+                bssid: info.bssid,
+                channel: info.channel,
+            }));
+            status.record_rssi(info.signal_strength);
+        }
+        Err(err) => log::warn!("Could not get the WiFi signal strength: {:?}", err),
     }
 }
 
@@ -153,18 +765,323 @@ pub async fn init(
     Ok(wifi)
 }
 
-pub fn handle_events(
+// Keep track of who's connected to the access point. This needs to start as soon as the access
+// point is up, before we've connected to the local network, so that we don't miss anyone.
+pub fn track_stations(
     eventloop: &EspSystemEventLoop,
+    signaler: Arc<io::Signaler>,
+    status: Arc<WifiStatus>,
+) -> anyhow::Result<EspSubscription<'static, eventloop::System>> {
+    let stop_when_empty = config::access_point_stop_when_empty()?;
+
+    let subscription = eventloop.subscribe::<WifiEvent, _>(move |event| match event {
+        WifiEvent::ApStaConnected(_) => {
+            let stations = status.stations.fetch_add(1, Ordering::Relaxed) + 1;
+            log::info!(
+                "Device connected to the access point ({} connected).",
+                stations
+            );
+        }
+        WifiEvent::ApStaDisconnected(_) => {
+            let stations = status
+                .stations
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |stations| {
+                    Some(stations.saturating_sub(1))
+                })
+                .map_or(0, |stations| stations.saturating_sub(1));
+
+            log::info!(
+                "Device disconnected from the access point ({} connected).",
+                stations
+            );
+
+            // Unlike when the toy drops off the local network, we don't get told when someone
+            // walks out of range of the access point, except that their device disconnects. If
+            // that was the last one, there's nobody left to control the toy.
+            if stations == 0 && stop_when_empty && !status.is_sta_connected() {
+                log::warn!("Nobody is connected anymore. Stopping.");
+
+                signaler.send(io::Signal::StopAuto);
+                signaler.stop_pump();
+            }
+        }
+        _ => {}
+    })?;
+
+    Ok(subscription)
+}
+
+// Scanning needs STA mode, so if we're only operating as an access point, we have to switch to
+// both for the duration of the scan.
+async fn scan_networks(wifi: &mut AsyncWifi<EspWifi<'static>>) -> anyhow::Result<Vec<Network>> {
+    let configuration = wifi.get_configuration()?;
+
+    if let Configuration::AccessPoint(ap_config) = &configuration {
+        wifi.set_configuration(&Configuration::Mixed(
+            ClientConfiguration::default(),
+            ap_config.clone(),
+        ))?;
+    }
+
+    let result = wifi.scan().await;
+
+    if let Configuration::AccessPoint(_) = &configuration {
+        wifi.set_configuration(&configuration)?;
+    }
+
+    let mut networks: Vec<Network> = Vec::new();
+
+    // Hidden networks don't have an SSID, so there's no point listing them.
+    for access_point in result?
+        .into_iter()
+        .filter(|access_point| !access_point.ssid.is_empty())
+    {
+        match networks
+            .iter_mut()
+            .find(|network| network.ssid == access_point.ssid.as_str())
+        {
+            Some(network) if network.rssi >= access_point.signal_strength => {}
+            Some(network) => {
+                network.rssi = access_point.signal_strength;
+                network.auth_method = access_point.auth_method;
+            }
+            None => networks.push(Network {
+                ssid: access_point.ssid.to_string(),
+                rssi: access_point.signal_strength,
+                auth_method: access_point.auth_method,
+            }),
+        }
+    }
+
+    networks.sort_by_key(|network| cmp::Reverse(network.rssi));
+
+    Ok(networks)
+}
+
+// Connect to the local network, and then reconnect whenever we lose the connection. In between
+// attempts, and once we're connected, do whatever else the rest of the device asks of us.
+fn stay_connected<P>(
+    wifi: &mut AsyncWifi<EspWifi<'static>>,
+    nvs_part: EspNvsPartition<P>,
+    sysloop: EspSystemEventLoop,
+    signaler: Arc<io::Signaler>,
+    handle: WifiHandle,
+    commands: mpsc::Receiver<Command>,
+) -> anyhow::Result<Never>
+where
+    P: NvsPartitionId,
+{
+    let status = handle.status();
+    let max_failures = config::wifi_max_failures()?;
+    let hostname = config::wifi_hostname()?;
+
+    let mut mdns = EspMdns::take()?;
+    configure_mdns(&mut mdns, &hostname)?;
+
+    // Don't drop these.
+    let _disconnects = track_disconnects(&sysloop, Arc::clone(status))?;
+    let _subscription = handle_events(
+        &sysloop,
+        Arc::clone(&signaler),
+        Arc::clone(status),
+        handle.commands.clone(),
+    )?;
+
+    let mut connecting = config::wifi_is_configured(nvs_part.clone())?
+        .then(|| Connecting::start(max_failures, status));
+
+    loop {
+        // While we're connecting, we wait for the next attempt to be due. Otherwise, we keep an
+        // eye on the signal strength.
+        let timeout = connecting
+            .as_ref()
+            .map_or(RSSI_SAMPLE_INTERVAL, Connecting::timeout);
+
+        let command = match commands.recv_timeout(timeout) {
+            Ok(command) => command,
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                match connecting.take() {
+                    Some(due) => {
+                        connecting = due.attempt(wifi, nvs_part.clone(), status);
+
+                        // Announce ourselves on the network we just joined.
+                        if status.is_sta_connected() {
+                            if let Err(err) = configure_mdns(&mut mdns, &hostname) {
+                                log::warn!("Could not configure mDNS: {:?}", err);
+                            }
+                        }
+                    }
+                    None if status.is_sta_connected() => sample_link(wifi, status),
+                    None => {}
+                }
+
+                continue;
+            }
+            Err(mpsc::RecvTimeoutError::Disconnected) => bail!("WiFi handle was dropped."),
+        };
+
+        match command {
+            Command::Reconnect => {
+                // The settings may have changed since we lost the connection, and we may have
+                // already connected to the new network or be on our way.
+                if status.is_sta_connected()
+                    || connecting.is_some()
+                    || !matches!(wifi.get_configuration(), Ok(Configuration::Mixed(..)))
+                {
+                    continue;
+                }
+
+                if let Err(err) = config::set_wifi_ip_addr(nvs_part.clone(), None) {
+                    log::warn!("Could not clear the IP address: {:?}", err);
+                }
+
+                connecting = Some(Connecting::start(max_failures, status));
+            }
+            Command::Scan => {
+                log::info!("Scanning for WiFi networks...");
+
+                match block_on(scan_networks(wifi)) {
+                    Ok(networks) => {
+                        log::info!("Found {} WiFi networks.", networks.len());
+
+                        status.set_scan(Scan::Done {
+                            at: Instant::now(),
+                            networks,
+                        });
+                    }
+                    Err(err) => {
+                        log::warn!("Could not scan for WiFi networks: {:?}", err);
+                        status.set_scan(Scan::Failed);
+                    }
+                }
+            }
+            Command::ApplySettings => {
+                log::info!("Applying new WiFi settings...");
+
+                connecting = switch_settings(
+                    wifi,
+                    nvs_part.clone(),
+                    max_failures,
+                    status,
+                    &signaler,
+                    connecting,
+                );
+            }
+            Command::TestCredentials { ssid, password } => {
+                log::info!("Testing WiFi credentials for {:?}...", ssid);
+
+                // If we were in the middle of connecting, we carry on from where we were
+                // afterward, unless the new network works and we switch to it instead.
+                match block_on(test_credentials(wifi, status, &ssid, &password)) {
+                    Ok(None) => {
+                        log::info!("WiFi credentials work. Saving them.");
+
+                        if let Err(err) = config::set_wifi_credentials(
+                            nvs_part.clone(),
+                            Some(&ssid),
+                            Some(password.as_str()).filter(|password| !password.is_empty()),
+                        ) {
+                            log::error!("Could not save WiFi credentials: {:?}", err);
+                            status.set_credential_test(CredentialTest::Error);
+                            continue;
+                        }
+
+                        status.set_credential_test(CredentialTest::Passed);
+
+                        connecting = switch_settings(
+                            wifi,
+                            nvs_part.clone(),
+                            max_failures,
+                            status,
+                            &signaler,
+                            connecting,
+                        );
+                    }
+                    Ok(Some(failure)) => {
+                        log::warn!("WiFi credentials don't work: {:?}", failure);
+                        status.set_credential_test(CredentialTest::Failed(failure));
+                    }
+                    Err(err) => {
+                        log::warn!("Could not test WiFi credentials: {:?}", err);
+                        status.set_credential_test(CredentialTest::Error);
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Connecting to the local network can take a while, or never happen at all, so it happens in the
+// background. The toy can still be controlled over its access point in the meantime. This takes
+// ownership of the WiFi so it stays up for as long as the device is running.
+pub fn keep_connected<P>(
+    mut wifi: AsyncWifi<EspWifi<'static>>,
+    nvs_part: EspNvsPartition<P>,
+    sysloop: EspSystemEventLoop,
+    signaler: Arc<io::Signaler>,
+    handle: WifiHandle,
+    commands: mpsc::Receiver<Command>,
+) -> anyhow::Result<()>
+where
+    P: NvsPartitionId + Send + Sync + 'static,
+{
+    thread::Builder::new()
+        .stack_size(WIFI_STACK_SIZE)
+        .spawn(move || {
+            let Err(err) = stay_connected(&mut wifi, nvs_part, sysloop, signaler, handle, commands);
+            log::error!("{:?}", err);
+
+            // If the WiFi thread can't carry on, starting over is the best we can do.
+            hal::reset::restart();
+        })?;
+
+    Ok(())
+}
+
+// Keep track of why the driver disconnects us, so that `try_connect` can tell the user why it
+// couldn't connect. This has to be in place before the first attempt.
+fn track_disconnects(
+    eventloop: &EspSystemEventLoop,
+    status: Arc<WifiStatus>,
+) -> anyhow::Result<EspSubscription<'static, eventloop::System>> {
+    Ok(
+        eventloop.subscribe::<StaDisconnected, _>(move |event: StaDisconnected| {
+            // We disconnect on purpose between attempts, so that's never why an attempt failed.
+            if u32::from(event.reason) != wifi_err_reason_t_WIFI_REASON_ASSOC_LEAVE {
+                status.set_disconnect_reason(event.reason);
+            }
+        })?,
+    )
+}
+
+fn handle_events(
+    eventloop: &EspSystemEventLoop,
+    signaler: Arc<io::Signaler>,
+    status: Arc<WifiStatus>,
+    commands: mpsc::Sender<Command>,
 ) -> anyhow::Result<EspSubscription<'static, eventloop::System>> {
     Ok(eventloop.subscribe::<WifiEvent, _>(move |event| {
+        // Failed attempts to connect show up as disconnects too, but those are handled by
+        // `connect`. We only care about losing a connection we already had.
         if let WifiEvent::StaDisconnected = event {
-            log::warn!("WiFi disconnected. Resetting...");
+            if !status.is_sta_connected() {
+                return;
+            }
 
-            // There is probably a more elegant solution to reconnecting to WiFi, but I wasn't able
-            // to figure it out. This approach has the benefit of ensuring the toy stops whatever
-            // it's doing once it disconnects (and the user isn't able to control it anymore). This
-            // is an important safety feature for a sex toy.
-            hal::reset::restart();
+            log::warn!("WiFi disconnected. Reconnecting...");
+
+            status.set_connection(Connection::Disconnected);
+            status.set_link(None);
+
+            // The user can't control the toy anymore, so it shouldn't be able to fire, and any
+            // scheduled squirts shouldn't go off. This is an important safety feature for a sex
+            // toy. Once we're back on the network, someone has to arm it again.
+            signaler.send(io::Signal::Disarm);
+
+            if commands.send(Command::Reconnect).is_err() {
+                log::error!("Nothing is reconnecting to WiFi. Resetting...");
+                hal::reset::restart();
+            }
         }
     })?)
 }
@@ -0,0 +1,44 @@
+use std::ffi::CStr;
+
+use esp_idf_svc::{
+    eventloop::{EspEvent, EspEventDeserializer, EspEventSource},
+    sys::{wifi_event_sta_disconnected_t, wifi_event_t_WIFI_EVENT_STA_DISCONNECTED},
+    wifi::WifiEvent,
+};
+
+// `WifiEvent::StaDisconnected` doesn't say why we were disconnected, so we read the reason out of
+// the raw event ourselves. It's one of the driver's `WIFI_REASON_*` codes.
+//
+// This is the only place in the firmware that needs unsafe code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StaDisconnected {
+    pub reason: u16,
+}
+
+// SAFETY: This is the event source `WifiEvent` uses, and we only subscribe to the one event whose
+// payload we know the type of.
+#[allow(unsafe_code)]
+unsafe impl EspEventSource for StaDisconnected {
+    fn source() -> Option<&'static CStr> {
+        WifiEvent::source()
+    }
+
+    fn event_id() -> Option<i32> {
+        Some(wifi_event_t_WIFI_EVENT_STA_DISCONNECTED as i32)
+    }
+}
+
+impl EspEventDeserializer for StaDisconnected {
+    type Data<'a> = StaDisconnected;
+
+    #[allow(unsafe_code)]
+    fn deserialize<'a>(data: &EspEvent<'a>) -> Self::Data<'a> {
+        // SAFETY: The driver always sends this payload along with this event, and `event_id`
+        // makes sure this is the only event we get.
+        let payload = unsafe { data.as_payload::<wifi_event_sta_disconnected_t>() };
+
+        Self {
+            reason: payload.reason.into(),
+        }
+    }
+}
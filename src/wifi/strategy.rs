@@ -0,0 +1,175 @@
+use std::{cmp, time::Duration};
+
+// In my testing, it can sometimes take the device a few attempts to connect to the local network,
+// even with a strong signal.
+//
+// While it's prudent to use exponential backoff, we also want to get the device connected ASAP,
+// because sex toys are a particular class of device that users have very little patience for
+// debugging (and why should they). So we make *n* "eager" attempts first, before we start applying
+// backoff.
+//
+// We also cap the maximum backoff; a mobile device such as this may be on an unstable network or
+// move in and out of range of networks, so we don't want to give up entirely.
+//
+// We need to be somewhat aggressive about connection attempts because users won't have the benefit
+// of log messages or helpful code comments to explain why they can't get their sex toy to work.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectStrategy {
+    Eager { attempt: u32 },
+    Backoff { time: Duration },
+}
+
+impl Default for ConnectStrategy {
+    fn default() -> Self {
+        Self::Eager { attempt: 0 }
+    }
+}
+
+impl ConnectStrategy {
+    pub const EAGER_ATTEMPTS: u32 = 3;
+    pub const MAX_BACKOFF: Duration = Duration::from_secs(2u64.pow(4));
+    pub const BACKOFF_MULTIPLIER: u32 = 2;
+
+    pub fn next_attempt(&mut self) {
+        match self {
+            Self::Eager { attempt: attempts } if *attempts >= Self::EAGER_ATTEMPTS => {
+                *self = Self::Backoff {
+                    time: Duration::from_secs(1),
+                };
+            }
+            Self::Eager { attempt: attempts } => {
+                *attempts += 1;
+            }
+            Self::Backoff { time } => {
+                *time = cmp::min(*time * Self::BACKOFF_MULTIPLIER, Self::MAX_BACKOFF);
+            }
+        }
+    }
+
+    // How long to wait before making the attempt.
+    pub fn delay(&self) -> Duration {
+        match self {
+            Self::Eager { .. } => Duration::ZERO,
+            Self::Backoff { time } => *time,
+        }
+    }
+}
+
+// Counts attempts to connect to the local network, and decides how to make the next one. If we
+// still can't connect after `max_failures` attempts, we give up and fall back to AP mode only,
+// rather than keep trying forever. Like the governor, this never reads the time itself.
+#[derive(Debug)]
+pub struct ConnectAttempts {
+    strategy: ConnectStrategy,
+    attempts: u32,
+    // Zero means we never give up.
+    max_failures: u32,
+}
+
+impl ConnectAttempts {
+    pub fn new(max_failures: u32) -> Self {
+        Self {
+            strategy: ConnectStrategy::default(),
+            attempts: 0,
+            max_failures,
+        }
+    }
+
+    // Start another attempt, returning which attempt it is (starting at 1) and how to make it.
+    pub fn next_attempt(&mut self) -> (u32, ConnectStrategy) {
+        self.strategy.next_attempt();
+        self.attempts += 1;
+
+        (self.attempts, self.strategy)
+    }
+
+    // Whether every attempt so far has failed and we've run out, so it's time to fall back to AP
+    // mode only.
+    pub fn is_exhausted(&self) -> bool {
+        self.max_failures > 0 && self.attempts >= self.max_failures
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secs(secs: u64) -> Duration {
+        Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn starts_eager_and_then_backs_off() {
+        let mut attempts = ConnectAttempts::new(0);
+
+        let strategies = (0..9).map(|_| attempts.next_attempt()).collect::<Vec<_>>();
+
+        assert_eq!(
+            strategies,
+            vec![
+                (1, ConnectStrategy::Eager { attempt: 1 }),
+                (2, ConnectStrategy::Eager { attempt: 2 }),
+                (3, ConnectStrategy::Eager { attempt: 3 }),
+                (4, ConnectStrategy::Backoff { time: secs(1) }),
+                (5, ConnectStrategy::Backoff { time: secs(2) }),
+                (6, ConnectStrategy::Backoff { time: secs(4) }),
+                (7, ConnectStrategy::Backoff { time: secs(8) }),
+                (8, ConnectStrategy::Backoff { time: secs(16) }),
+                // The backoff stops growing once it hits the cap.
+                (9, ConnectStrategy::Backoff { time: secs(16) }),
+            ]
+        );
+    }
+
+    #[test]
+    fn only_waits_when_backing_off() {
+        assert_eq!(
+            ConnectStrategy::Eager { attempt: 2 }.delay(),
+            Duration::ZERO
+        );
+        assert_eq!(ConnectStrategy::Backoff { time: secs(4) }.delay(), secs(4));
+    }
+
+    #[test]
+    fn falls_back_after_max_failures() {
+        let mut attempts = ConnectAttempts::new(5);
+
+        for _ in 0..4 {
+            attempts.next_attempt();
+            assert!(!attempts.is_exhausted());
+        }
+
+        // Failures while backing off count the same as eager ones.
+        assert_eq!(
+            attempts.next_attempt(),
+            (5, ConnectStrategy::Backoff { time: secs(2) })
+        );
+        assert!(attempts.is_exhausted());
+    }
+
+    #[test]
+    fn can_fall_back_after_a_single_failure() {
+        let mut attempts = ConnectAttempts::new(1);
+
+        assert!(!attempts.is_exhausted());
+
+        attempts.next_attempt();
+
+        assert!(attempts.is_exhausted());
+    }
+
+    #[test]
+    fn never_falls_back_without_max_failures() {
+        let mut attempts = ConnectAttempts::new(0);
+
+        for _ in 0..1000 {
+            attempts.next_attempt();
+        }
+
+        assert!(!attempts.is_exhausted());
+        assert_eq!(
+            attempts.next_attempt(),
+            (1001, ConnectStrategy::Backoff { time: secs(16) })
+        );
+    }
+}